@@ -0,0 +1,32 @@
+// Copyright © Piotr Zolnierek
+
+//! Compares escaping text without chars to escape, which takes the fast path,
+//! with text which has to be escaped char by char. Run with `cargo bench`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use xml_writer::XmlWriter;
+
+const ROUNDS: usize = 100_000;
+
+fn escape(text: &str) -> Duration {
+    let mut xml = XmlWriter::compact_mode(Vec::with_capacity(ROUNDS * text.len() * 2));
+    xml.begin_elem("root").unwrap();
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        xml.text(black_box(text)).unwrap();
+    }
+    let elapsed = start.elapsed();
+    black_box(xml.into_inner());
+    elapsed
+}
+
+fn main() {
+    let clean = "The quick brown fox jumps over the lazy dog, again and again and again.";
+    let dirty = "The quick brown fox jumps over the lazy dog, again & again & again > <.";
+    println!("clean text:    {:?}", escape(clean));
+    println!("escaped text:  {:?}", escape(dirty));
+    let emoji = "The 🦊 quick brown fox 🦊 jumps over the lazy 🐶 & again & again > <.";
+    println!("escaped emoji: {:?}", escape(emoji));
+}
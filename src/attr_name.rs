@@ -0,0 +1,45 @@
+// Copyright © Piotr Zolnierek
+
+/// A name of an attr for `XmlWriter::attr` and `XmlWriter::attr_esc`, like a variant of
+/// an enum of the attrs a schema allows, to avoid typos in stringly typed names
+///
+/// ```
+/// use xml_writer::{AttrName, XmlWriter};
+///
+/// enum Link {
+///     Href,
+///     Rel,
+/// }
+///
+/// impl AttrName for Link {
+///     fn as_str(&self) -> &str {
+///         match self {
+///             Link::Href => "href",
+///             Link::Rel => "rel",
+///         }
+///     }
+/// }
+///
+/// let mut xml = XmlWriter::compact_mode(Vec::new());
+/// xml.begin_elem("a").unwrap();
+/// xml.attr_esc(Link::Href, "/x?a&b").unwrap();
+/// xml.attr(Link::Rel, "next").unwrap();
+/// xml.close().unwrap();
+/// assert_eq!(xml.into_inner(), b"<a href=\"/x?a&amp;b\" rel=\"next\"></a>");
+/// ```
+pub trait AttrName {
+    /// The name as written
+    fn as_str(&self) -> &str;
+}
+
+impl<T: AsRef<str> + ?Sized> AttrName for &T {
+    fn as_str(&self) -> &str {
+        (**self).as_ref()
+    }
+}
+
+impl AttrName for String {
+    fn as_str(&self) -> &str {
+        self
+    }
+}
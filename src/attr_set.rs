@@ -0,0 +1,41 @@
+// Copyright © Piotr Zolnierek
+
+/// A reusable set of attrs, to write the same attrs to many elems
+/// with `XmlWriter::apply_attrs`
+///
+/// ```
+/// use xml_writer::{AttrSet, XmlWriter};
+///
+/// let cell = AttrSet::new().add("class", "cell").add("align", "left");
+/// let mut xml = XmlWriter::compact_mode(Vec::new());
+/// xml.begin_elem("row").unwrap();
+/// for text in ["a", "b"] {
+///     xml.begin_elem("td").unwrap();
+///     xml.apply_attrs(&cell).unwrap();
+///     xml.text(text).unwrap();
+///     xml.end_elem().unwrap();
+/// }
+/// xml.close().unwrap();
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AttrSet<'s> {
+    attrs: Vec<(&'s str, &'s str)>,
+}
+
+impl<'s> AttrSet<'s> {
+    /// Create an empty set
+    pub fn new() -> AttrSet<'s> {
+        AttrSet { attrs: Vec::new() }
+    }
+
+    /// Add an attr, the value is written as is like with `XmlWriter::attr`
+    pub fn add(mut self, name: &'s str, value: &'s str) -> AttrSet<'s> {
+        self.attrs.push((name, value));
+        self
+    }
+
+    /// Iterate over the attrs as `(name, value)` in the order they were added
+    pub fn iter(&self) -> impl Iterator<Item = (&'s str, &'s str)> + '_ {
+        self.attrs.iter().copied()
+    }
+}
@@ -0,0 +1,425 @@
+// Copyright © Piotr Zolnierek
+
+use std::io::{self, Write};
+
+/// Where the checker is within the markup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// character data between tags
+    Text,
+    /// a reference like `&amp;` in text, or in an attr value delimited by the quote
+    Ref(Option<u8>),
+    /// after `<`
+    Open,
+    /// the name of a start tag
+    StartName,
+    /// within a start tag, `true` if whitespace separates the next attr
+    Tag(bool),
+    /// the name of an attr
+    AttrName,
+    /// after an attr name, expecting `=`
+    Eq,
+    /// after `=`, expecting the quote
+    Quote,
+    /// an attr value delimited by the quote
+    Value(u8),
+    /// after the `/` of a self-closing tag, expecting `>`
+    SelfClose,
+    /// the name of an end tag
+    EndName,
+    /// after the name of an end tag, expecting `>`
+    EndTail,
+    /// after `<!`, collecting `--`, `[CDATA[` or `DOCTYPE`
+    Bang,
+    /// a comment, with the number of `-` seen last
+    Comment(usize),
+    /// a CDATA section, with the number of `]` seen last
+    CData(usize),
+    /// a processing instruction, `true` after a `?`
+    Pi(bool),
+    /// a DOCTYPE, with the depth of `[` and the open quote
+    Doctype(usize, Option<u8>),
+}
+
+/// A sink which checks the xml written through it for well-formedness: a single root
+/// elem without text around it, balanced tags, valid chars, quoted attr values and
+/// complete references, comments, CDATA sections and processing instructions. The first
+/// violation is reported as an `io::Error` of kind `InvalidData` and nothing of the
+/// offending write is passed on.
+///
+/// ```
+/// use xml_writer::{CheckedWriter, XmlWriter};
+///
+/// let mut xml = XmlWriter::compact_mode(CheckedWriter::new(Vec::new()));
+/// xml.begin_elem("root").unwrap();
+/// xml.text("raw writes follow").unwrap();
+/// xml.write("<unbalanced>").unwrap();
+/// // `</root>` does not match `<unbalanced>`
+/// assert!(xml.close().is_err());
+/// ```
+#[derive(Debug)]
+pub struct CheckedWriter<W: Write> {
+    inner: W,
+    state: State,
+    /// names of the open elems
+    stack: Vec<Vec<u8>>,
+    /// the name or keyword being read
+    name: Vec<u8>,
+    /// number of bytes checked
+    pos: usize,
+    /// if `true` the root elem has been closed
+    root_closed: bool,
+    /// number of elems on `stack` opened before the current write
+    base: usize,
+    /// elems opened before the current write and closed by it, to reopen them on error
+    closed: Vec<Vec<u8>>,
+    /// `name` before the current write, to restore it on error
+    saved_name: Vec<u8>,
+}
+
+/// Allowed in names, anything but whitespace, controls and chars with a meaning in markup
+fn is_name_byte(b: u8) -> bool {
+    b > b' ' && !b"\"'&<>=/!?;".contains(&b)
+}
+
+/// Whitespace within tags
+fn is_space(b: u8) -> bool {
+    matches!(b, b' ' | b'\t' | b'\n' | b'\r')
+}
+
+/// `true` if the body of a reference like `amp`, `#169` or `#xA9` is valid
+fn is_ref(body: &[u8]) -> bool {
+    match body {
+        [b'#', b'x', hex @ ..] => !hex.is_empty() && hex.iter().all(u8::is_ascii_hexdigit),
+        [b'#', digits @ ..] => !digits.is_empty() && digits.iter().all(u8::is_ascii_digit),
+        _ => !body.is_empty() && body.iter().all(|&b| is_name_byte(b)),
+    }
+}
+
+impl<W: Write> CheckedWriter<W> {
+    /// Check everything written to `inner`
+    pub fn new(inner: W) -> CheckedWriter<W> {
+        CheckedWriter {
+            inner,
+            state: State::Text,
+            stack: Vec::new(),
+            name: Vec::new(),
+            pos: 0,
+            root_closed: false,
+            base: 0,
+            closed: Vec::new(),
+            saved_name: Vec::new(),
+        }
+    }
+
+    /// Get a reference to the inner Writer
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Check the document is complete, all elems are closed and no markup is left open,
+    /// and return the inner Writer
+    pub fn finish(self) -> io::Result<W> {
+        if let Some(name) = self.stack.last() {
+            return Err(self.error(&format!("elem {:?} is not closed", String::from_utf8_lossy(name))));
+        }
+        if self.state != State::Text {
+            return Err(self.error("the markup is incomplete"));
+        }
+        Ok(self.inner)
+    }
+
+    fn error(&self, msg: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, format!("malformed xml at byte {}: {}", self.pos, msg))
+    }
+
+    /// Check `buf`, leaving the state unchanged if it is malformed. Instead of copying
+    /// the stack up front, the elems closed by `buf` are kept to reopen them on error.
+    fn check(&mut self, buf: &[u8]) -> io::Result<()> {
+        let (state, pos, root_closed) = (self.state, self.pos, self.root_closed);
+        self.base = self.stack.len();
+        self.saved_name.clear();
+        self.saved_name.extend_from_slice(&self.name);
+        let result = buf.iter().try_for_each(|&b| self.step(b));
+        if result.is_err() {
+            (self.state, self.pos, self.root_closed) = (state, pos, root_closed);
+            self.stack.truncate(self.base);
+            self.stack.extend(self.closed.drain(..).rev());
+            std::mem::swap(&mut self.name, &mut self.saved_name);
+        }
+        self.closed.clear();
+        result
+    }
+
+    /// Close the innermost elem, `true` if its name is `name`, `None` if none is open
+    fn pop_elem(&mut self) -> Option<bool> {
+        let open = self.stack.pop()?;
+        let matches = open == self.name;
+        if self.stack.is_empty() {
+            self.root_closed = true;
+        }
+        if self.stack.len() < self.base {
+            self.base = self.stack.len();
+            self.closed.push(open);
+        }
+        Some(matches)
+    }
+
+    /// Advance the state by one byte
+    fn step(&mut self, b: u8) -> io::Result<()> {
+        if b < b' ' && !is_space(b) {
+            return Err(self.error("invalid control char"));
+        }
+        self.state = match self.state {
+            State::Text if self.stack.is_empty() => match b {
+                b'<' => State::Open,
+                _ if is_space(b) => State::Text,
+                _ => return Err(self.error("text outside of the root elem")),
+            },
+            State::Text => match b {
+                b'<' => State::Open,
+                b'&' => self.begin_name(State::Ref(None)),
+                _ => State::Text,
+            },
+            State::Ref(quote) => match b {
+                b';' if is_ref(&self.name) => match quote {
+                    Some(quote) => State::Value(quote),
+                    None => State::Text,
+                },
+                b';' => return Err(self.error("invalid reference")),
+                b'#' if self.name.is_empty() => self.push_name(b),
+                _ if is_name_byte(b) => self.push_name(b),
+                _ => return Err(self.error("unterminated reference")),
+            },
+            State::Open => match b {
+                b'/' => self.begin_name(State::EndName),
+                b'!' => self.begin_name(State::Bang),
+                b'?' => State::Pi(false),
+                _ if is_name_byte(b) && self.root_closed && self.stack.is_empty() => {
+                    return Err(self.error("a second root elem"));
+                }
+                _ if is_name_byte(b) => {
+                    self.name.clear();
+                    self.push_name(b);
+                    State::StartName
+                }
+                _ => return Err(self.error("invalid start of a tag")),
+            },
+            State::StartName => match b {
+                _ if is_name_byte(b) => self.push_name(b),
+                _ => {
+                    let name = std::mem::take(&mut self.name);
+                    self.stack.push(name);
+                    self.in_tag(b, true)?
+                }
+            },
+            State::Tag(spaced) => self.in_tag(b, spaced)?,
+            State::AttrName => match b {
+                _ if is_name_byte(b) => State::AttrName,
+                b'=' => State::Quote,
+                _ if is_space(b) => State::Eq,
+                _ => return Err(self.error("invalid attr name")),
+            },
+            State::Eq => match b {
+                b'=' => State::Quote,
+                _ if is_space(b) => State::Eq,
+                _ => return Err(self.error("expected `=` after the attr name")),
+            },
+            State::Quote => match b {
+                b'"' | b'\'' => State::Value(b),
+                _ if is_space(b) => State::Quote,
+                _ => return Err(self.error("unquoted attr value")),
+            },
+            State::Value(quote) => match b {
+                _ if b == quote => State::Tag(false),
+                b'<' => return Err(self.error("`<` in an attr value")),
+                b'&' => self.begin_name(State::Ref(Some(quote))),
+                _ => State::Value(quote),
+            },
+            State::SelfClose => match b {
+                b'>' => {
+                    self.pop_elem();
+                    State::Text
+                }
+                _ => return Err(self.error("expected `>` after `/`")),
+            },
+            State::EndName => match b {
+                _ if is_name_byte(b) => self.push_name(b),
+                _ => {
+                    match self.pop_elem() {
+                        Some(true) => {}
+                        Some(false) => return Err(self.error("end tag does not match the start tag")),
+                        None => return Err(self.error("end tag without start tag")),
+                    }
+                    self.end_tail(b)?
+                }
+            },
+            State::EndTail => self.end_tail(b)?,
+            State::Bang => {
+                self.name.push(b);
+                match self.name.as_slice() {
+                    b"--" => State::Comment(0),
+                    b"[CDATA[" => State::CData(0),
+                    b"DOCTYPE" => State::Doctype(0, None),
+                    name if b"--".starts_with(name) || b"[CDATA[".starts_with(name) || b"DOCTYPE".starts_with(name) => {
+                        State::Bang
+                    }
+                    _ => return Err(self.error("invalid markup declaration")),
+                }
+            }
+            State::Comment(dashes) => match b {
+                b'>' if dashes == 2 => State::Text,
+                _ if dashes == 2 => return Err(self.error("`--` in a comment")),
+                b'-' => State::Comment(dashes + 1),
+                _ => State::Comment(0),
+            },
+            State::CData(brackets) => match b {
+                b']' => State::CData(brackets + 1),
+                b'>' if brackets >= 2 => State::Text,
+                _ => State::CData(0),
+            },
+            State::Pi(question) => match b {
+                b'>' if question => State::Text,
+                _ => State::Pi(b == b'?'),
+            },
+            State::Doctype(depth, Some(quote)) => State::Doctype(depth, (b != quote).then_some(quote)),
+            State::Doctype(depth, None) => match b {
+                b'"' | b'\'' => State::Doctype(depth, Some(b)),
+                b'[' => State::Doctype(depth + 1, None),
+                b']' if depth > 0 => State::Doctype(depth - 1, None),
+                b'>' if depth == 0 => State::Text,
+                _ => State::Doctype(depth, None),
+            },
+        };
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn begin_name(&mut self, state: State) -> State {
+        self.name.clear();
+        state
+    }
+
+    fn push_name(&mut self, b: u8) -> State {
+        self.name.push(b);
+        self.state
+    }
+
+    /// Within a start tag after the name or an attr
+    fn in_tag(&mut self, b: u8, spaced: bool) -> io::Result<State> {
+        Ok(match b {
+            b'>' => State::Text,
+            b'/' => State::SelfClose,
+            _ if is_space(b) => State::Tag(true),
+            _ if is_name_byte(b) && spaced => State::AttrName,
+            _ if is_name_byte(b) => return Err(self.error("attrs not separated by whitespace")),
+            _ => return Err(self.error("invalid char in a start tag")),
+        })
+    }
+
+    /// After the name of an end tag
+    fn end_tail(&mut self, b: u8) -> io::Result<State> {
+        Ok(match b {
+            b'>' => State::Text,
+            _ if is_space(b) => State::EndTail,
+            _ => return Err(self.error("invalid char in an end tag")),
+        })
+    }
+}
+
+impl<W: Write> Write for CheckedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check(buf)?;
+        self.inner.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CheckedWriter;
+    use crate::XmlWriter;
+    use std::io::Write;
+
+    fn check(xml: &str) -> Result<(), String> {
+        let mut writer = CheckedWriter::new(Vec::new());
+        // feed byte by byte to check the state is carried across writes
+        for b in xml.bytes() {
+            writer.write_all(&[b]).map_err(|err| err.to_string())?;
+        }
+        writer.finish().map(|_| ()).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn well_formed() {
+        let docs = [
+            "<?xml version=\"1.0\" encoding=\"utf-8\" ?>\n<root/>",
+            "<!DOCTYPE doc [\n  <!ENTITY e \"a > b\">\n]><doc>&e;&#169;&#xA9;</doc>",
+            "<a x='1' y = \"&amp;'\"><!-- c - d --><![CDATA[<]]]]><![CDATA[>]]><?pi a?b?></a>",
+            "<ns:a xmlns:ns=\"urn:a\">\n  <ns:b />\n</ns:a >",
+            "<!-- before -->\n<a><!----></a>\n<!-- after --><?pi?>\n",
+        ];
+        for doc in docs {
+            assert_eq!(check(doc), Ok(()), "{}", doc);
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        let docs = [
+            ("<a><b></a>", "end tag does not match"),
+            ("<a>", "elem \"a\" is not closed"),
+            ("</a>", "end tag without start tag"),
+            ("<a x=1/>", "unquoted attr value"),
+            ("<a x=\"1\"y=\"2\"/>", "not separated"),
+            ("<a x=\"<\"/>", "`<` in an attr value"),
+            ("<a>&amp</a>", "unterminated reference"),
+            ("<a>&;</a>", "invalid reference"),
+            ("<a>\0</a>", "invalid control char"),
+            ("<!-- a -- b -->", "`--` in a comment"),
+            ("<a><!-- a ---></a>", "`--` in a comment"),
+            ("text<a/>", "text outside of the root elem"),
+            ("<a/>text", "text outside of the root elem"),
+            ("<a/>&amp;", "text outside of the root elem"),
+            ("<a/><b/>", "a second root elem"),
+            ("<a><![CDATA[x</a>", "not closed"),
+            ("<!ELEMENT a>", "invalid markup declaration"),
+        ];
+        for (doc, msg) in docs {
+            let err = check(doc).unwrap_err();
+            assert!(err.contains(msg), "{}: {}", doc, err);
+        }
+    }
+
+    #[test]
+    fn rejected_write() {
+        let mut xml = XmlWriter::compact_mode(CheckedWriter::new(Vec::new()));
+        xml.begin_elem("root").unwrap();
+        xml.text("ok").unwrap();
+        assert!(xml.write("</wrong>").is_err());
+        xml.end_elem().unwrap();
+        let writer = xml.into_inner();
+        assert_eq!(writer.get_ref().as_slice(), b"<root>ok</root>");
+        writer.finish().unwrap();
+
+        // the elems closed by a rejected write are open again
+        let mut writer = CheckedWriter::new(Vec::new());
+        writer.write_all(b"<a><b><c>").unwrap();
+        assert!(writer.write_all(b"</c></b></x>").is_err());
+        writer.write_all(b"</c></b></a>").unwrap();
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn decl_auto() {
+        let mut xml = XmlWriter::compact_mode(CheckedWriter::new(Vec::new()));
+        xml.decl_auto().unwrap();
+        xml.empty_elem("root").unwrap();
+        let writer = xml.into_inner();
+        assert_eq!(writer.get_ref().as_slice(), b"<?xml version=\"1.0\" encoding=\"utf-8\" ?>\n<root/>");
+    }
+}
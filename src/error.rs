@@ -0,0 +1,91 @@
+// Copyright © Piotr Zolnierek
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// A misuse of the `XmlWriter` which would result in malformed xml.
+///
+/// It is reported as an `io::Error` of kind `InvalidInput` carrying the `XmlError`,
+/// use `XmlError::from_io` to get it back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum XmlError {
+    /// The xml declaration was written after other content
+    DeclarationNotFirst,
+    /// The name can not be used for an element or attribute
+    InvalidName(String),
+    /// The document has no root element
+    EmptyDocument,
+    /// The namespace prefix is not declared by any open element
+    UndeclaredPrefix(String),
+    /// The text was expected to contain only whitespace
+    NotWhitespace(String),
+    /// An attribute or namespace declaration was written while no start tag was open
+    AttrOutsideElement,
+    /// A declaration of the internal DOCTYPE subset was written outside of it
+    OutsideDoctypeSubset,
+    /// The element would be nested deeper than the maximum depth
+    MaxDepthExceeded(usize),
+    /// The text contains U+0000, which can not be represented in xml at all
+    NullCharacter,
+    /// Raw output was written in safe mode
+    RawWriteForbidden,
+    /// No open element has the name
+    NoSuchOpenElement(String),
+    /// The output does not fit into the fixed buffer of the size
+    BufferFull(usize),
+    /// No prefix is bound to the namespace uri
+    UnboundNamespace(String),
+    /// Text other than whitespace was written outside of the root element
+    TextAtRoot,
+    /// The data of a processing instruction contains its end `?>`
+    InvalidPiData(String),
+    /// The name can not be used as encoding in the xml declaration
+    InvalidEncoding(String),
+    /// The json is malformed or has a field which can not be an attr, at the byte offset
+    #[cfg(feature = "json")]
+    InvalidJson(usize),
+}
+
+impl XmlError {
+    /// Get the `XmlError` carried by an `io::Error`, if any
+    pub fn from_io(err: &io::Error) -> Option<&XmlError> {
+        err.get_ref().and_then(|err| err.downcast_ref())
+    }
+}
+
+impl fmt::Display for XmlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            XmlError::DeclarationNotFirst => {
+                write!(f, "the xml declaration must be the first thing in the document")
+            }
+            XmlError::InvalidName(name) => write!(f, "invalid name {:?}", name),
+            XmlError::EmptyDocument => write!(f, "the document has no root element"),
+            XmlError::UndeclaredPrefix(prefix) => write!(f, "undeclared namespace prefix {:?}", prefix),
+            XmlError::NotWhitespace(text) => write!(f, "not whitespace only {:?}", text),
+            XmlError::AttrOutsideElement => write!(f, "no open start tag to write the attribute to"),
+            XmlError::OutsideDoctypeSubset => write!(f, "no open internal DOCTYPE subset"),
+            XmlError::MaxDepthExceeded(depth) => write!(f, "elements nested deeper than {}", depth),
+            XmlError::NullCharacter => write!(f, "the null character can not be written in xml"),
+            XmlError::RawWriteForbidden => write!(f, "raw output is forbidden in safe mode"),
+            XmlError::NoSuchOpenElement(name) => write!(f, "no open element {:?}", name),
+            XmlError::BufferFull(size) => write!(f, "the output does not fit into {} bytes", size),
+            XmlError::UnboundNamespace(uri) => write!(f, "no prefix bound to namespace {:?}", uri),
+            XmlError::TextAtRoot => write!(f, "text is not allowed outside of the root element"),
+            XmlError::InvalidPiData(data) => write!(f, "processing instruction data contains `?>` {:?}", data),
+            XmlError::InvalidEncoding(name) => write!(f, "invalid encoding name {:?}", name),
+            #[cfg(feature = "json")]
+            XmlError::InvalidJson(pos) => write!(f, "invalid json at byte {}", pos),
+        }
+    }
+}
+
+impl Error for XmlError {}
+
+impl From<XmlError> for io::Error {
+    fn from(err: XmlError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
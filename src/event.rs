@@ -0,0 +1,81 @@
+// Copyright © Piotr Zolnierek
+
+/// A piece of xml for `XmlWriter::write_event`, modeled after the events
+/// of pull parsers like `quick-xml`. Attribute values and text get escaped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// A start tag, see `XmlWriter::begin_elem`
+    Start {
+        /// name of the element
+        name: &'a str,
+        /// attributes as `(name, value)`
+        attrs: &'a [(&'a str, &'a str)],
+    },
+    /// The end tag of the innermost open element, see `XmlWriter::end_elem`
+    End,
+    /// A self-closing element, see `XmlWriter::empty_elem`
+    Empty {
+        /// name of the element
+        name: &'a str,
+        /// attributes as `(name, value)`
+        attrs: &'a [(&'a str, &'a str)],
+    },
+    /// Text content, see `XmlWriter::text`
+    Text(&'a str),
+    /// A CDATA section, see `XmlWriter::cdata`
+    CData(&'a str),
+    /// A comment, see `XmlWriter::comment`
+    Comment(&'a str),
+    /// A processing instruction, see `XmlWriter::pi`
+    PI {
+        /// target of the processing instruction
+        target: &'a str,
+        /// data of the processing instruction, may be empty
+        data: &'a str,
+    },
+    /// The xml declaration, see `XmlWriter::dtd`
+    Decl {
+        /// the declared encoding
+        encoding: &'a str,
+    },
+}
+
+/// The kind of a node written by the `XmlWriter`, see `XmlWriter::last_node`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum NodeKind {
+    /// A start tag, see `XmlWriter::begin_elem`
+    StartTag,
+    /// An end tag, see `XmlWriter::end_elem`
+    EndTag,
+    /// A self-closing element, see `XmlWriter::empty_elem`
+    Empty,
+    /// Text content, see `XmlWriter::text`
+    Text,
+    /// A CDATA section, see `XmlWriter::cdata`
+    CData,
+    /// A comment, see `XmlWriter::comment`
+    Comment,
+    /// A processing instruction, see `XmlWriter::pi`
+    Pi,
+}
+
+/// A node about to be written, passed to the observer set by `XmlWriter::set_observer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WriterEvent<'e> {
+    /// The start tag of the named element
+    Open(&'e str),
+    /// The end tag of the named element, which may be self-closed instead
+    Close(&'e str),
+    /// The named self-closing element
+    Empty(&'e str),
+    /// Text content
+    Text,
+    /// A CDATA section
+    CData,
+    /// A comment
+    Comment,
+    /// A processing instruction with the target
+    Pi(&'e str),
+}
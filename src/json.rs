@@ -0,0 +1,336 @@
+// Copyright © Piotr Zolnierek
+
+use std::borrow::Cow;
+use std::io;
+
+use crate::XmlError;
+
+type Result = io::Result<()>;
+
+/// How deep objects and arrays may nest in skipped values
+const MAX_DEPTH: usize = 128;
+
+/// Called with the key, the value and the position of each field of an object
+type Field<'f, 'j> = dyn FnMut(&str, Option<Cow<'j, str>>, usize) -> Result + 'f;
+
+/// Call `field` with the key and the value of each field of the JSON object `json`,
+/// numbers as written and bools as `true` or `false`. Null, object and array fields
+/// are skipped if `skip_nested`, else they are an error like malformed json.
+pub(crate) fn for_each_field(json: &str, skip_nested: bool, mut field: impl FnMut(&str, &str) -> Result) -> Result {
+    let mut parser = Parser { json, pos: 0, depth: 0 };
+    parser.skip_ws();
+    if parser.peek() != Some(b'{') {
+        return Err(parser.error());
+    }
+    parser.object(&mut |key, value, pos| match value {
+        Some(value) => field(key, &value),
+        None if skip_nested => Ok(()),
+        None => Err(XmlError::InvalidJson(pos).into()),
+    })?;
+    parser.skip_ws();
+    if parser.pos < json.len() {
+        return Err(parser.error());
+    }
+    Ok(())
+}
+
+/// The position within the json text and the depth of the skipped value
+struct Parser<'j> {
+    json: &'j str,
+    pos: usize,
+    depth: usize,
+}
+
+impl<'j> Parser<'j> {
+    fn error(&self) -> io::Error {
+        XmlError::InvalidJson(self.pos).into()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.json.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_ws(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    /// Consume `byte` if it is next
+    fn eat(&mut self, byte: u8) -> bool {
+        let next = self.peek() == Some(byte);
+        self.pos += next as usize;
+        next
+    }
+
+    /// Consume `byte` after optional whitespace, which has to be next
+    fn expect(&mut self, byte: u8) -> Result {
+        self.skip_ws();
+        match self.eat(byte) {
+            true => Ok(()),
+            false => Err(self.error()),
+        }
+    }
+
+    /// Consume `word` if it is next
+    fn literal(&mut self, word: &str) -> bool {
+        let next = self.json.as_bytes()[self.pos..].starts_with(word.as_bytes());
+        self.pos += if next { word.len() } else { 0 };
+        next
+    }
+
+    /// An object, calling `field` with the key, the value and the position of each field
+    fn object(&mut self, field: &mut Field<'_, 'j>) -> Result {
+        self.expect(b'{')?;
+        self.skip_ws();
+        if self.eat(b'}') {
+            return Ok(());
+        }
+        loop {
+            let key = self.string()?;
+            self.expect(b':')?;
+            self.skip_ws();
+            let pos = self.pos;
+            let value = self.value()?;
+            field(&key, value, pos)?;
+            self.skip_ws();
+            if !self.eat(b',') {
+                return self.expect(b'}');
+            }
+        }
+    }
+
+    /// A value after optional whitespace, `None` for null, objects and arrays, which are skipped
+    fn value(&mut self) -> io::Result<Option<Cow<'j, str>>> {
+        self.skip_ws();
+        match self.peek() {
+            Some(b'"') => self.string().map(Some),
+            Some(b'{' | b'[') if self.depth == MAX_DEPTH => Err(self.error()),
+            Some(b'{') => {
+                self.depth += 1;
+                self.object(&mut |_, _, _| Ok(()))?;
+                self.depth -= 1;
+                Ok(None)
+            }
+            Some(b'[') => {
+                self.depth += 1;
+                self.pos += 1;
+                self.skip_ws();
+                if !self.eat(b']') {
+                    loop {
+                        self.value()?;
+                        self.skip_ws();
+                        if !self.eat(b',') {
+                            break;
+                        }
+                    }
+                    self.expect(b']')?;
+                }
+                self.depth -= 1;
+                Ok(None)
+            }
+            _ if self.literal("true") => Ok(Some(Cow::Borrowed("true"))),
+            _ if self.literal("false") => Ok(Some(Cow::Borrowed("false"))),
+            _ if self.literal("null") => Ok(None),
+            _ => self.number().map(|number| Some(Cow::Borrowed(number))),
+        }
+    }
+
+    /// A string after optional whitespace, unescaped
+    fn string(&mut self) -> io::Result<Cow<'j, str>> {
+        self.expect(b'"')?;
+        let mut unescaped: Option<String> = None;
+        let mut start = self.pos;
+        loop {
+            match self.peek() {
+                Some(b'"') => {
+                    let rest = &self.json[start..self.pos];
+                    self.pos += 1;
+                    return Ok(match unescaped {
+                        Some(mut unescaped) => {
+                            unescaped.push_str(rest);
+                            Cow::Owned(unescaped)
+                        }
+                        None => Cow::Borrowed(rest),
+                    });
+                }
+                Some(b'\\') => {
+                    let unescaped = unescaped.get_or_insert_with(String::new);
+                    unescaped.push_str(&self.json[start..self.pos]);
+                    self.pos += 1;
+                    unescaped.push(self.escape()?);
+                    start = self.pos;
+                }
+                Some(0x20..) => self.pos += 1,
+                _ => return Err(self.error()),
+            }
+        }
+    }
+
+    /// The char of an escape sequence after the `\`
+    fn escape(&mut self) -> io::Result<char> {
+        let c = match self.peek() {
+            Some(b'"') => '"',
+            Some(b'\\') => '\\',
+            Some(b'/') => '/',
+            Some(b'b') => '\u{8}',
+            Some(b'f') => '\u{c}',
+            Some(b'n') => '\n',
+            Some(b'r') => '\r',
+            Some(b't') => '\t',
+            Some(b'u') => {
+                self.pos += 1;
+                let high = self.hex()?;
+                let code = match high {
+                    0xD800..0xDC00 if self.literal("\\u") => {
+                        let low = self.hex()?;
+                        if !(0xDC00..0xE000).contains(&low) {
+                            return Err(self.error());
+                        }
+                        0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+                    }
+                    _ => high,
+                };
+                // a lone surrogate is no char
+                return char::from_u32(code).ok_or_else(|| self.error());
+            }
+            _ => return Err(self.error()),
+        };
+        self.pos += 1;
+        Ok(c)
+    }
+
+    /// The four hex digits of a `\u` escape sequence
+    fn hex(&mut self) -> io::Result<u32> {
+        let digits = self.json.as_bytes().get(self.pos..self.pos + 4).ok_or_else(|| self.error())?;
+        if !digits.iter().all(u8::is_ascii_hexdigit) {
+            return Err(self.error());
+        }
+        let code = digits.iter().fold(0, |code, &d| code << 4 | (d as char).to_digit(16).unwrap_or(0));
+        self.pos += 4;
+        Ok(code)
+    }
+
+    /// A number, as written
+    fn number(&mut self) -> io::Result<&'j str> {
+        let start = self.pos;
+        self.eat(b'-');
+        if !self.eat(b'0') {
+            self.digits()?;
+        }
+        if self.eat(b'.') {
+            self.digits()?;
+        }
+        if self.eat(b'e') || self.eat(b'E') {
+            let _ = self.eat(b'+') || self.eat(b'-');
+            self.digits()?;
+        }
+        Ok(&self.json[start..self.pos])
+    }
+
+    /// One or more decimal digits
+    fn digits(&mut self) -> Result {
+        let start = self.pos;
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        match self.pos > start {
+            true => Ok(()),
+            false => Err(self.error()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::for_each_field;
+    use crate::{XmlError, XmlWriter};
+    use std::str;
+
+    fn fields(json: &str, skip_nested: bool) -> Result<Vec<(String, String)>, Option<usize>> {
+        let mut fields = Vec::new();
+        let result = for_each_field(json, skip_nested, |key, value| {
+            fields.push((key.to_string(), value.to_string()));
+            Ok(())
+        });
+        match result {
+            Ok(()) => Ok(fields),
+            Err(err) => match XmlError::from_io(&err) {
+                Some(XmlError::InvalidJson(pos)) => Err(Some(*pos)),
+                _ => Err(None),
+            },
+        }
+    }
+
+    fn field(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn scalar_fields() {
+        let json = " { \"s\" : \"a \\\"b\\\" \\u00e9\\ud83d\\ude00\\n\", \"n\": -1.5e+3, \"z\":0, \"t\": true, \"f\": false } ";
+        assert_eq!(
+            fields(json, false),
+            Ok(vec![
+                field("s", "a \"b\" \u{e9}\u{1f600}\n"),
+                field("n", "-1.5e+3"),
+                field("z", "0"),
+                field("t", "true"),
+                field("f", "false"),
+            ])
+        );
+        assert_eq!(fields("{}", false), Ok(vec![]));
+    }
+
+    #[test]
+    fn nested_fields() {
+        let json = r#"{"a": null, "b": {"c": [1, {"d": "]"}]}, "e": [], "f": "g"}"#;
+        assert_eq!(fields(json, true), Ok(vec![field("f", "g")]));
+        assert_eq!(fields(json, false), Err(Some(6)));
+        let deep = format!("{{\"a\": {}{}}}", "[".repeat(200), "]".repeat(200));
+        assert!(fields(&deep, true).is_err());
+    }
+
+    #[test]
+    fn malformed() {
+        let docs = [
+            "",
+            "[]",
+            "\"a\"",
+            "{",
+            "{\"a\"}",
+            "{\"a\": 1,}",
+            "{\"a\": 01}",
+            "{\"a\": 1.}",
+            "{\"a\": tru}",
+            "{\"a\": \"\\x\"}",
+            "{\"a\": \"\\ud800\"}",
+            "{\"a\": \"\n\"}",
+            "{\"a\": 1} x",
+            "{a: 1}",
+        ];
+        for json in docs {
+            assert!(fields(json, true).is_err(), "{:?}", json);
+        }
+    }
+
+    #[test]
+    fn attrs_from_json() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("item").unwrap();
+        xml.attrs_from_json(r#"{"id": 7, "name": "a & \"b\"", "on": true}"#).unwrap();
+        assert!(xml.attrs_from_json(r#"{"x": 1, "y": [2]}"#).is_err());
+        assert!(xml.attrs_from_json(r#"{"x": 1, "a b": 2}"#).is_err());
+        xml.set_json_skip_nested(true);
+        xml.attrs_from_json(r#"{"x": 1, "y": [2]}"#).unwrap();
+        xml.end_elem().unwrap();
+        let err = xml.attrs_from_json("{}").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::AttrOutsideElement));
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<item id=\"7\" name=\"a &amp; &quot;b&quot;\" on=\"true\" x=\"1\"></item>"
+        );
+    }
+}
@@ -5,6 +5,29 @@
 #![deny(missing_docs)]
 #![deny(unsafe_code)]
 
+mod attr_name;
+mod attr_set;
+#[cfg(feature = "verify")]
+mod checked_writer;
+mod error;
+mod event;
+#[cfg(feature = "json")]
+mod json;
+mod macros;
+mod namespace_context;
+mod options;
+mod slice_writer;
+mod tee_writer;
 mod xml_writer;
 
-pub use xml_writer::XmlWriter;
+pub use attr_name::AttrName;
+pub use attr_set::AttrSet;
+#[cfg(feature = "verify")]
+pub use checked_writer::CheckedWriter;
+pub use error::XmlError;
+pub use event::{Event, NodeKind, WriterEvent};
+pub use namespace_context::NamespaceContext;
+pub use options::{AttrWrapStyle, FieldPolicy, NameCase, OutputMode, Quote, SelfCloseSpace};
+pub use slice_writer::SliceWriter;
+pub use tee_writer::TeeWriter;
+pub use xml_writer::{Checkpoint, EncodingAware, Truncate, XmlWriter};
@@ -0,0 +1,162 @@
+// Copyright © Piotr Zolnierek
+
+/// Write a tree of elements with a declarative syntax.
+///
+/// The macro expands to calls on the given `XmlWriter`, propagating errors with `?`,
+/// so it has to be used in a function returning `std::io::Result`.
+///
+/// - `name { ... }` writes an element with children
+/// - `name[id = "1", "xml:lang" = lang] { ... }` writes an element with escaped attributes
+/// - `name("text");` and `name[id = "1"]("text");` write an element with escaped text
+/// - `name;` and `name[id = "1"];` write an element without content
+/// - `"text";` and `(expr);` write escaped text
+///
+/// Names are identifiers or string literals, any `AsRef<str>` can be used for values and text.
+///
+/// ```
+/// use xml_writer::{xml_write, XmlWriter};
+///
+/// fn write() -> std::io::Result<Vec<u8>> {
+///     let mut xml = XmlWriter::compact_mode(Vec::new());
+///     let name = String::from("world");
+///     xml_write!(xml, root[id = "1"] {
+///         greeting("hello");
+///         "list-item"[value = "en"];
+///         name { (name); }
+///     });
+///     Ok(xml.into_inner())
+/// }
+///
+/// assert_eq!(
+///     write().unwrap(),
+///     b"<root id=\"1\"><greeting>hello</greeting><list-item value=\"en\"/><name>world</name></root>"
+/// );
+/// ```
+#[macro_export]
+macro_rules! xml_write {
+    ($w:expr, $($nodes:tt)*) => {
+        $crate::xml_write!(@nodes $w; $($nodes)*);
+    };
+
+    (@nodes $w:expr;) => {};
+    (@nodes $w:expr; $name:tt [ $($attrs:tt)* ] { $($children:tt)* } $($rest:tt)*) => {
+        $w.begin_elem($crate::xml_write!(@name $name))?;
+        $crate::xml_write!(@attrs $w; $($attrs)*);
+        $crate::xml_write!(@nodes $w; $($children)*);
+        $w.end_elem()?;
+        $crate::xml_write!(@nodes $w; $($rest)*);
+    };
+    (@nodes $w:expr; $name:tt { $($children:tt)* } $($rest:tt)*) => {
+        $w.begin_elem($crate::xml_write!(@name $name))?;
+        $crate::xml_write!(@nodes $w; $($children)*);
+        $w.end_elem()?;
+        $crate::xml_write!(@nodes $w; $($rest)*);
+    };
+    (@nodes $w:expr; $name:tt [ $($attrs:tt)* ] ( $text:expr ) ; $($rest:tt)*) => {
+        $w.begin_elem($crate::xml_write!(@name $name))?;
+        $crate::xml_write!(@attrs $w; $($attrs)*);
+        $w.text(::core::convert::AsRef::<str>::as_ref(&$text))?;
+        $w.end_elem()?;
+        $crate::xml_write!(@nodes $w; $($rest)*);
+    };
+    (@nodes $w:expr; $name:tt ( $text:expr ) ; $($rest:tt)*) => {
+        $w.elem_text(
+            $crate::xml_write!(@name $name),
+            ::core::convert::AsRef::<str>::as_ref(&$text),
+        )?;
+        $crate::xml_write!(@nodes $w; $($rest)*);
+    };
+    (@nodes $w:expr; $name:tt [ $($attr:tt = $value:expr),* $(,)? ] ; $($rest:tt)*) => {
+        let attrs: &[(&str, &str)] = &[$((
+            $crate::xml_write!(@name $attr),
+            ::core::convert::AsRef::<str>::as_ref(&$value),
+        )),*];
+        $w.elem_from_map($crate::xml_write!(@name $name), attrs.iter().copied())?;
+        $crate::xml_write!(@nodes $w; $($rest)*);
+    };
+    (@nodes $w:expr; ( $text:expr ) ; $($rest:tt)*) => {
+        $w.text(::core::convert::AsRef::<str>::as_ref(&$text))?;
+        $crate::xml_write!(@nodes $w; $($rest)*);
+    };
+    (@nodes $w:expr; $text:literal ; $($rest:tt)*) => {
+        $w.text($text)?;
+        $crate::xml_write!(@nodes $w; $($rest)*);
+    };
+    (@nodes $w:expr; $name:ident ; $($rest:tt)*) => {
+        $w.empty_elem(stringify!($name))?;
+        $crate::xml_write!(@nodes $w; $($rest)*);
+    };
+
+    (@attrs $w:expr;) => {};
+    (@attrs $w:expr; $name:tt = $value:expr $(, $($rest:tt)*)?) => {
+        $w.attr_esc(
+            $crate::xml_write!(@name $name),
+            ::core::convert::AsRef::<str>::as_ref(&$value),
+        )?;
+        $crate::xml_write!(@attrs $w; $($($rest)*)?);
+    };
+
+    (@name $name:ident) => {
+        stringify!($name)
+    };
+    (@name $name:literal) => {
+        $name
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::XmlWriter;
+    use std::io;
+    use std::str;
+
+    fn document(xml: &mut XmlWriter<Vec<u8>>) -> io::Result<()> {
+        let id = 2.to_string();
+        xml_write!(xml, root[id = "1", "xml:lang" = "en"] {
+            "text";
+            node[id = id]("<text>");
+            leaf("leaf");
+            empty;
+            empty[a = "1"];
+            empty[a = "<", b = "2",];
+            parent {
+                child[a = "&", b = "2",] {}
+                ("tail");
+            }
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn xml_write() {
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        document(&mut xml).unwrap();
+        xml.assert_balanced();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root id=\"1\" xml:lang=\"en\">\n  text\n  <node id=\"2\">\n    &lt;text&gt;\n  </node>\n  <leaf>leaf</leaf>\n  <empty/>\n  <empty a=\"1\"/>\n  <empty a=\"&lt;\" b=\"2\"/>\n  <parent>\n    <child a=\"&amp;\" b=\"2\"/>\n    tail\n  </parent>\n</root>");
+    }
+
+    #[test]
+    fn xml_write_empty() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        let result: io::Result<()> = (|| {
+            xml_write!(xml, root { a[h = "x"]; b; c[]; });
+            Ok(())
+        })();
+        result.unwrap();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root><a h=\"x\"/><b/><c/></root>");
+    }
+
+    #[test]
+    fn xml_write_error() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        let result: io::Result<()> = (|| {
+            xml_write!(xml, root["bad name" = "1"];);
+            Ok(())
+        })();
+        assert!(result.is_err());
+    }
+}
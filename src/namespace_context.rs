@@ -0,0 +1,84 @@
+// Copyright © Piotr Zolnierek
+
+/// Prefix to namespace uri bindings in nested scopes, maintained by the application
+/// independent of the open elems. `XmlWriter::begin_elem_ctx` and `XmlWriter::attr_ctx`
+/// resolve the prefix of a uri by it and declare it where the output needs it.
+///
+/// ```
+/// use xml_writer::{NamespaceContext, XmlWriter};
+///
+/// let mut ctx = NamespaceContext::new();
+/// ctx.declare(Some("s"), "urn:soap");
+/// let mut xml = XmlWriter::compact_mode(Vec::new());
+/// xml.begin_elem_ctx(&ctx, "urn:soap", "Envelope").unwrap();
+/// xml.begin_elem_ctx(&ctx, "urn:soap", "Body").unwrap();
+/// xml.close().unwrap();
+/// assert_eq!(
+///     xml.into_inner(),
+///     b"<s:Envelope xmlns:s=\"urn:soap\"><s:Body></s:Body></s:Envelope>"
+/// );
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NamespaceContext<'n> {
+    /// bindings as `(scope, prefix, uri)`, `None` for the default namespace
+    bindings: Vec<(usize, Option<&'n str>, &'n str)>,
+    scope: usize,
+}
+
+impl<'n> NamespaceContext<'n> {
+    /// Create a context without bindings
+    pub fn new() -> NamespaceContext<'n> {
+        NamespaceContext::default()
+    }
+
+    /// Begin a nested scope, bindings declared in it hide those of the outer scopes
+    pub fn push_scope(&mut self) {
+        self.scope += 1;
+    }
+
+    /// End the current scope and drop its bindings, the outermost scope is never ended
+    pub fn pop_scope(&mut self) {
+        if self.scope == 0 {
+            return;
+        }
+        while self.bindings.last().is_some_and(|&(scope, _, _)| scope == self.scope) {
+            self.bindings.pop();
+        }
+        self.scope -= 1;
+    }
+
+    /// Bind `prefix` to `uri` in the current scope, `None` for the default namespace
+    pub fn declare(&mut self, prefix: Option<&'n str>, uri: &'n str) {
+        self.bindings.push((self.scope, prefix, uri));
+    }
+
+    /// The uri bound to `prefix`, `None` if it is unbound
+    pub fn uri(&self, prefix: Option<&str>) -> Option<&'n str> {
+        self.bindings
+            .iter()
+            .rev()
+            .find(|&&(_, bound, _)| bound == prefix)
+            .map(|&(_, _, uri)| uri)
+    }
+
+    /// The innermost prefix bound to `uri` and not hidden by a later binding,
+    /// `Some(None)` for the default namespace, `None` if there is none
+    pub fn prefix(&self, uri: &str) -> Option<Option<&'n str>> {
+        self.prefixes(uri).next()
+    }
+
+    /// The prefixes bound to `uri` and not hidden by a later binding, innermost first
+    fn prefixes<'s>(&'s self, uri: &'s str) -> impl Iterator<Item = Option<&'n str>> + 's {
+        self.bindings
+            .iter()
+            .rev()
+            .filter(move |&&(_, _, bound)| bound == uri)
+            .map(|&(_, prefix, _)| prefix)
+            .filter(|&prefix| self.uri(prefix) == Some(uri))
+    }
+
+    /// The innermost prefix, not the default namespace, bound to `uri`
+    pub(crate) fn attr_prefix(&self, uri: &str) -> Option<&'n str> {
+        self.prefixes(uri).flatten().next()
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright © Piotr Zolnierek
+
+/// How element and attribute names are cased on write, see `XmlWriter::set_name_case`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NameCase {
+    /// Names are written as given, xml names are case-sensitive
+    #[default]
+    AsIs,
+    /// Names are written in lowercase, like usual for html
+    Lower,
+    /// Names are written in uppercase
+    Upper,
+}
+
+/// When to put a space before the `/>` of self-closing elems,
+/// see `XmlWriter::set_self_close_space`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SelfCloseSpace {
+    /// Never, like `<br/>` and `<input type="x"/>`
+    #[default]
+    Never,
+    /// Always, like `<br />` and `<input type="x" />`
+    Always,
+    /// Only if the elem has attrs, like `<br/>` and `<input type="x" />`
+    WithAttrs,
+}
+
+/// The quote char delimiting attr values, see `XmlWriter::set_quote`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Quote {
+    /// Double quotes like `a="1"`
+    #[default]
+    Double,
+    /// Single quotes like `a='1'`
+    Single,
+}
+
+/// How the text of a field is written, see `XmlWriter::elem_text_policy`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FieldPolicy {
+    /// Escaped text like `a &lt; b`
+    #[default]
+    Escape,
+    /// A CDATA section like `<![CDATA[a < b]]>`, for content like embedded HTML
+    CData,
+}
+
+/// Where attrs after the first of a start tag are aligned when each is put on an own line,
+/// see `XmlWriter::set_attr_wrap`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttrWrapStyle {
+    /// Under the elem name, like `<a x="1"` and ` y="2">` below
+    AlignUnderName,
+    /// Under the first attr, like `<a x="1"` and `   y="2">` below
+    AlignUnderFirstAttr,
+    /// Indented by the number of spaces more than the start tag
+    FixedIndent(usize),
+}
+
+/// The layout of the output, see `XmlWriter::mode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    /// No line breaks or indentation, also in `canonical` mode
+    Compact,
+    /// Elems are put into own indented lines
+    Pretty,
+    /// Every node is put into an own indented line, also in `readable` mode
+    VeryPretty,
+}
@@ -0,0 +1,103 @@
+// Copyright © Piotr Zolnierek
+
+use std::io::{self, Write};
+
+use crate::{Truncate, XmlError};
+
+/// A sink writing into a fixed buffer like a stack allocated array, instead of allocating.
+/// A write which does not fit is an `XmlError::BufferFull` and writes nothing.
+///
+/// ```
+/// use xml_writer::{SliceWriter, XmlWriter};
+///
+/// let mut buf = [0u8; 64];
+/// let mut xml = XmlWriter::compact_mode(SliceWriter::new(&mut buf));
+/// xml.elem_text("msg", "hello").unwrap();
+/// let out = xml.into_inner();
+/// assert_eq!(out.written(), b"<msg>hello</msg>");
+/// ```
+#[derive(Debug)]
+pub struct SliceWriter<'b> {
+    buf: &'b mut [u8],
+    /// number of bytes written
+    used: usize,
+}
+
+impl<'b> SliceWriter<'b> {
+    /// Write into `buf` from its start
+    pub fn new(buf: &'b mut [u8]) -> SliceWriter<'b> {
+        SliceWriter { buf, used: 0 }
+    }
+
+    /// The number of bytes written so far
+    pub fn bytes_used(&self) -> usize {
+        self.used
+    }
+
+    /// The number of bytes left in the buffer
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.used
+    }
+
+    /// The bytes written so far
+    pub fn written(&self) -> &[u8] {
+        &self.buf[..self.used]
+    }
+}
+
+impl Write for SliceWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.remaining() {
+            return Err(XmlError::BufferFull(self.buf.len()).into());
+        }
+        self.buf[self.used..self.used + buf.len()].copy_from_slice(buf);
+        self.used += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Truncate for SliceWriter<'_> {
+    fn position(&self) -> usize {
+        self.used
+    }
+
+    fn truncate_to(&mut self, position: usize) {
+        self.used = self.used.min(position);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SliceWriter;
+    use crate::{XmlError, XmlWriter};
+
+    #[test]
+    fn buffer_full() {
+        let mut buf = [0u8; 16];
+        let mut xml = XmlWriter::compact_mode(SliceWriter::new(&mut buf));
+        xml.begin_elem("root").unwrap();
+        let err = xml.text("does not fit in").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::BufferFull(16)));
+        let out = xml.into_inner();
+        assert_eq!(out.written(), b"<root>");
+        assert_eq!(out.bytes_used(), 6);
+        assert_eq!(out.remaining(), 10);
+    }
+
+    #[test]
+    fn rollback() {
+        let mut buf = [0u8; 32];
+        let mut xml = XmlWriter::compact_mode(SliceWriter::new(&mut buf));
+        xml.begin_elem("root").unwrap();
+        let cp = xml.checkpoint();
+        xml.elem_text("a", "1").unwrap();
+        xml.rollback(cp);
+        xml.elem_text("b", "2").unwrap();
+        xml.close().unwrap();
+        assert_eq!(xml.into_inner().written(), b"<root><b>2</b></root>");
+    }
+}
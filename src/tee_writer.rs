@@ -0,0 +1,70 @@
+// Copyright © Piotr Zolnierek
+
+use std::io::{self, Write};
+
+/// A sink forwarding everything to two sinks, like the output and a log buffer
+///
+/// ```
+/// use xml_writer::{TeeWriter, XmlWriter};
+///
+/// let mut xml = XmlWriter::compact_mode(TeeWriter::new(Vec::new(), Vec::new()));
+/// xml.elem_text("msg", "hello").unwrap();
+/// let (out, log) = xml.into_inner().into_inner();
+/// assert_eq!(out, log);
+/// ```
+#[derive(Debug)]
+pub struct TeeWriter<A: Write, B: Write> {
+    a: A,
+    b: B,
+}
+
+impl<A: Write, B: Write> TeeWriter<A, B> {
+    /// Write everything to `a` and then to `b`
+    pub fn new(a: A, b: B) -> TeeWriter<A, B> {
+        TeeWriter { a, b }
+    }
+
+    /// Get references to both sinks
+    pub fn get_ref(&self) -> (&A, &B) {
+        (&self.a, &self.b)
+    }
+
+    /// Get both sinks back
+    pub fn into_inner(self) -> (A, B) {
+        (self.a, self.b)
+    }
+}
+
+impl<A: Write, B: Write> Write for TeeWriter<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.a.write_all(buf)?;
+        self.b.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.a.flush()?;
+        self.b.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TeeWriter;
+    use crate::XmlWriter;
+
+    #[test]
+    fn identical_bytes() {
+        let mut xml = XmlWriter::very_pretty_mode(TeeWriter::new(Vec::new(), Vec::new()));
+        xml.begin_elem("root").unwrap();
+        xml.attr_esc("a", "<1>").unwrap();
+        xml.elem_text("b", "text").unwrap();
+        xml.comment("c").unwrap();
+        xml.close().unwrap();
+        xml.flush().unwrap();
+
+        let (out, log) = xml.into_inner().into_inner();
+        assert_eq!(out, b"<root a=\"&lt;1&gt;\">\n  <b>text</b>\n  <!-- c -->\n</root>");
+        assert_eq!(out, log);
+    }
+}
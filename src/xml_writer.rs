@@ -3,31 +3,328 @@
 #![doc = include_str!("../README.md")]
 
 use std::fmt;
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
+use std::str;
+
+#[cfg(feature = "json")]
+use crate::json;
+use crate::{AttrName, AttrSet, AttrWrapStyle, Event, FieldPolicy, NameCase, NamespaceContext, NodeKind, OutputMode, Quote, SelfCloseSpace, WriterEvent, XmlError};
 
 pub type Result = io::Result<()>;
 
+/// Sort key of a buffered attr: the position in the attr order first, then in `canonical`
+/// mode namespace declarations by prefix, then attrs by namespace uri and local name
+type AttrKey = (usize, bool, String, String);
+
+/// Callback of `XmlWriter::set_observer`, `Send` and `Sync` to keep the writer so
+type Observer = Box<dyn FnMut(WriterEvent<'_>) + Send + Sync>;
+
+const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+
+/// An open elem on the stack
+#[derive(Debug, Clone)]
+struct Elem<'a> {
+    name: &'a str,
+    /// `true` if the elem has children, so it must not be self-closed
+    children: bool,
+    /// `true` if a child was put into an own line, so the end tag goes into an own line too
+    nested: bool,
+    /// `true` if the elem declares a namespace
+    declares_ns: bool,
+}
+
+/// The output buffered in `adaptive` mode, until it is known whether
+/// the document fits into `threshold` bytes when pretty printed
+struct Adaptive {
+    threshold: usize,
+    /// the output without line breaks
+    buf: Vec<u8>,
+    /// positions in `buf` where `pretty` mode breaks the line, and the depth to indent
+    breaks: Vec<(usize, usize)>,
+    /// length of the output when pretty printed
+    pretty_len: usize,
+}
+
+/// A sink which can be cut back to an earlier length, needed by `XmlWriter::rollback`
+pub trait Truncate {
+    /// The number of bytes written so far
+    fn position(&self) -> usize;
+    /// Drop all bytes after `position`
+    fn truncate_to(&mut self, position: usize);
+}
+
+impl Truncate for Vec<u8> {
+    fn position(&self) -> usize {
+        self.len()
+    }
+
+    fn truncate_to(&mut self, position: usize) {
+        self.truncate(position);
+    }
+}
+
+/// A sink which knows the encoding it writes, like a transcoding Writer,
+/// for `XmlWriter::decl_auto` to declare it. The default is UTF-8, which the std sinks
+/// report, while buffering and borrowing wrappers report the encoding of the inner sink.
+pub trait EncodingAware {
+    /// The name of the encoding, as declared in the xml declaration
+    fn encoding(&self) -> &str {
+        "utf-8"
+    }
+}
+
+impl EncodingAware for Vec<u8> {}
+
+impl EncodingAware for std::fs::File {}
+
+impl EncodingAware for io::Stdout {}
+
+impl EncodingAware for io::StdoutLock<'_> {}
+
+impl EncodingAware for io::Stderr {}
+
+impl EncodingAware for io::Sink {}
+
+impl<T> EncodingAware for io::Cursor<T> {}
+
+impl<W: Write + EncodingAware> EncodingAware for io::BufWriter<W> {
+    fn encoding(&self) -> &str {
+        self.get_ref().encoding()
+    }
+}
+
+impl<W: Write + EncodingAware> EncodingAware for io::LineWriter<W> {
+    fn encoding(&self) -> &str {
+        self.get_ref().encoding()
+    }
+}
+
+impl<W: EncodingAware + ?Sized> EncodingAware for &mut W {
+    fn encoding(&self) -> &str {
+        (**self).encoding()
+    }
+}
+
+impl<W: EncodingAware + ?Sized> EncodingAware for Box<W> {
+    fn encoding(&self) -> &str {
+        (**self).encoding()
+    }
+}
+
+impl EncodingAware for crate::SliceWriter<'_> {}
+
+impl<A: Write + EncodingAware, B: Write> EncodingAware for crate::TeeWriter<A, B> {
+    fn encoding(&self) -> &str {
+        self.get_ref().0.encoding()
+    }
+}
+
+#[cfg(feature = "verify")]
+impl<W: Write + EncodingAware> EncodingAware for crate::CheckedWriter<W> {
+    fn encoding(&self) -> &str {
+        self.get_ref().encoding()
+    }
+}
+
+/// The output position and state of an `XmlWriter`, taken by `XmlWriter::checkpoint`
+#[derive(Debug, Clone)]
+pub struct Checkpoint<'a> {
+    position: usize,
+    /// length of the `capture` buffer, if capturing
+    captured: Option<usize>,
+    /// length of the `adaptive` buffer, its line breaks and its pretty length, if buffering
+    buffered: Option<(usize, usize, usize)>,
+    stack: Vec<Elem<'a>>,
+    ns_stack: Vec<Option<&'a str>>,
+    bindings: Vec<(usize, Option<&'a str>, &'a str)>,
+    ns_restore: Vec<(usize, Option<&'a str>)>,
+    pending_attrs: Vec<(AttrKey, Vec<u8>)>,
+    deferred_comments: Vec<String>,
+    namespace: Option<&'a str>,
+    opened: bool,
+    in_subset: bool,
+    attrs_written: bool,
+    name_column: usize,
+    attr_column: usize,
+    last_node: Option<NodeKind>,
+    wrote_anything: bool,
+    wrote_trailing_newline: bool,
+    wrote_root: bool,
+    children: bool,
+    newline: bool,
+    mixed_depth: Option<usize>,
+    in_tag: bool,
+    in_ref: bool,
+    brackets: usize,
+    line: usize,
+    column: usize,
+}
+
 /// The XmlWriter himself
 pub struct XmlWriter<'a, W: Write> {
-    /// `bool` indicates self closing
-    stack: Vec<(&'a str, bool)>,
+    stack: Vec<Elem<'a>>,
     /// `bool` indicates self closing
     ns_stack: Vec<Option<&'a str>>,
-    writer: Box<W>,
+    /// namespace bindings declared on the open elems as `(depth, prefix, uri)`
+    bindings: Vec<(usize, Option<&'a str>, &'a str)>,
+    /// `namespace` values to restore when the elem at `depth` gets closed
+    ns_restore: Vec<(usize, Option<&'a str>)>,
+    /// attrs of the open start tag, buffered in `canonical` mode to be written sorted
+    pending_attrs: Vec<(AttrKey, Vec<u8>)>,
+    writer: W,
+    /// if set, all output goes here instead of into `writer`
+    capture: Option<Vec<u8>>,
+    /// if set, all output is buffered to decide between `pretty` and `compact`
+    adaptive: Option<Adaptive>,
     opened: bool,
     /// if `true` it will indent all opening elements
     pretty: bool,
-    /// an XML namespace that all elements will be part of, unless `None`
+    /// an XML namespace prefix that all elements will be part of, unless `None`.
+    /// A change applies to all elems begun afterwards, end tags always get the
+    /// prefix of their start tag. Closing an elem begun with `begin_elem_ns` or
+    /// `begin_elem_default_ns` restores the value from before the elem.
+    /// Elems with a qualified name like `soap:Envelope` do not get the prefix.
     pub namespace: Option<&'a str>,
     /// includes `pretty`, additional:
     /// - puts closing elements into own line
     /// - elements without children are self-closing
     /// - indentation with single tab
     very_pretty: bool,
+    /// in `very pretty` mode keeps text inline with its elem, unless
+    /// the elem already has children in own lines
+    inline_text: bool,
+    /// if `true` the output follows the rules of canonical xml
+    canonical: bool,
+    /// if `true` the internal subset of a DOCTYPE is open
+    in_subset: bool,
+    /// if `true` raw output is forbidden
+    safe: bool,
+    /// maximum nesting depth of elems, if any
+    max_depth: Option<usize>,
+    /// if `true` nothing is indented within elems containing text
+    preserve_mixed: bool,
+    /// depth of the outermost open elem containing text, if `preserve_mixed`
+    mixed_depth: Option<usize>,
+    /// if `true` misuse which would produce malformed xml is reported as `XmlError`
+    strict: bool,
+    /// if `true` something besides a leading BOM has been written already
+    wrote_anything: bool,
+    /// if `true` the root elem has been written already
+    wrote_root: bool,
+    /// name of the elem `close` writes if the document has no root elem
+    empty_root: Option<&'a str>,
+    /// if `true` `close` ends the document with a line break
+    trailing_newline: bool,
+    /// if `true` the output ends with the trailing line break
+    wrote_trailing_newline: bool,
+    /// if `true` the output ends within a tag, after a `<` without `>`
+    in_tag: bool,
+    /// if `true` the output ends within a reference, after a `&` without `;`
+    in_ref: bool,
+    /// number of `]` the output ends with, up to 2, so a `>` following them gets escaped
+    brackets: usize,
+    /// if `true` all non-ASCII chars are escaped as numeric character references
+    ascii_only: bool,
+    /// if set, escaped chars it rejects are written as numeric character references
+    char_filter: Option<Box<dyn Fn(char) -> bool + Send + Sync>>,
+    /// chars replaced by the string in text content, like `©` by `&#169;`
+    text_substitutions: Vec<(char, String)>,
+    /// if set, called with each node before it gets written
+    observer: Option<Observer>,
+    /// if `true` entity references in text are not escaped again
+    preserve_entities: bool,
+    /// if `true` whitespace in escaped attr values is trimmed and collapsed
+    normalize_attr_whitespace: bool,
+    /// if `true` only chars which would break the markup are escaped
+    minimal_escaping: bool,
+    /// names of attrs to write in this order before all other attrs
+    attr_order: Vec<String>,
+    /// column to wrap text at in the pretty modes, if any
+    wrap_text_at: Option<usize>,
+    /// if `true` text and attr values are escaped by the rules of HTML serialization
+    html_mode: bool,
+    /// the quote char delimiting attr values
+    quote: Quote,
+    /// casing of element and attribute names
+    name_case: NameCase,
+    /// if `false` elems are never self-closed
+    self_close: bool,
+    /// if `true` the root elem is never self-closed
+    explicit_close_root: bool,
+    /// if `true` empty elems declaring namespaces are never self-closed
+    explicit_close_ns: bool,
+    /// when to put a space before the `/>` of self-closing elems
+    self_close_space: SelfCloseSpace,
+    /// if `false` a comment does not keep its elem from being self-closed
+    comments_count_as_children: bool,
+    /// if `true` `attrs_from_json` skips null, object and array fields instead of failing
+    #[cfg(feature = "json")]
+    json_skip_nested: bool,
+    /// comments of the open start tag, written after the elem if it gets self-closed
+    deferred_comments: Vec<String>,
+    /// if `true` the current start tag has attrs
+    attrs_written: bool,
+    /// in pretty mode put each attr after the first on an own line, aligned as the style says
+    attr_wrap: Option<AttrWrapStyle>,
+    /// column of the name of the current start tag
+    name_column: usize,
+    /// column of the first attr of the current start tag
+    attr_column: usize,
+    /// the kind of node written last
+    last_node: Option<NodeKind>,
     /// if `true` current elem has children
     children: bool,
     /// newline indicator
-    newline: bool
+    newline: bool,
+    /// line of the output position, starting at 1
+    line: usize,
+    /// column of the output position in chars, starting at 1
+    column: usize,
+}
+
+/// Length of the entity or character reference like `&amp;`, `&#169;` or `&#xA9;`
+/// at the start of `text`, 0 if there is none
+fn entity_ref_len(text: &str) -> usize {
+    let Some(body) = text.strip_prefix('&') else {
+        return 0;
+    };
+    let Some(end) = body.find(';') else {
+        return 0;
+    };
+    let name = &body[..end];
+    let valid = if let Some(hex) = name.strip_prefix("#x") {
+        !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit())
+    } else if let Some(digits) = name.strip_prefix('#') {
+        !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+    } else {
+        let mut chars = name.chars();
+        chars.next().is_some_and(|c| c.is_alphabetic() || c == '_' || c == ':')
+            && chars.all(|c| c.is_alphanumeric() || matches!(c, '_' | ':' | '.' | '-'))
+    };
+    if valid { end + 2 } else { 0 }
+}
+
+/// A `fmt::Write` escaping everything written into an attr value or text, see `XmlWriter::attr_with`
+struct Escaping<'x, 'a, W: Write> {
+    xml: &'x mut XmlWriter<'a, W>,
+    /// `true` if writing an attr value
+    attr: bool,
+    /// the error of the underlying Writer, as `fmt::Error` can not carry it
+    err: Option<io::Error>,
+}
+
+impl<'x, 'a, W: Write> fmt::Write for Escaping<'x, 'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.xml.escape(s, self.attr).map_err(|err| {
+            self.err = Some(err);
+            fmt::Error
+        })
+    }
+}
+
+/// Number of chars in UTF-8 encoded `bytes`
+fn char_count(bytes: &[u8]) -> usize {
+    bytes.iter().filter(|&&b| b & 0xC0 != 0x80).count()
 }
 
 impl<'a, W: Write> fmt::Debug for XmlWriter<'a, W> {
@@ -46,467 +343,4577 @@ impl<'a, W: Write> XmlWriter<'a, W> {
         XmlWriter {
             stack: Vec::new(),
             ns_stack: Vec::new(),
-            writer: Box::new(writer),
+            bindings: Vec::new(),
+            ns_restore: Vec::new(),
+            pending_attrs: Vec::new(),
+            writer,
+            capture: None,
+            adaptive: None,
             opened: false,
             pretty: false,
             namespace: None,
             very_pretty: false,
+            inline_text: false,
+            canonical: false,
+            in_subset: false,
+            safe: false,
+            max_depth: None,
+            preserve_mixed: false,
+            mixed_depth: None,
+            strict: false,
+            wrote_anything: false,
+            wrote_root: false,
+            empty_root: None,
+            trailing_newline: false,
+            wrote_trailing_newline: false,
+            in_tag: false,
+            in_ref: false,
+            brackets: 0,
+            ascii_only: false,
+            char_filter: None,
+            text_substitutions: Vec::new(),
+            observer: None,
+            normalize_attr_whitespace: false,
+            preserve_entities: false,
+            minimal_escaping: false,
+            attr_order: Vec::new(),
+            wrap_text_at: None,
+            html_mode: false,
+            quote: Quote::Double,
+            name_case: NameCase::AsIs,
+            self_close: true,
+            explicit_close_root: false,
+            explicit_close_ns: false,
+            self_close_space: SelfCloseSpace::Never,
+            comments_count_as_children: true,
+            #[cfg(feature = "json")]
+            json_skip_nested: false,
+            deferred_comments: Vec::new(),
+            attrs_written: false,
+            attr_wrap: None,
+            name_column: 1,
+            attr_column: 1,
+            last_node: None,
             children: false,
             newline: false,
+            line: 1,
+            column: 1,
         }
     }
 
-    /// Create a new writer with `pretty` output
+    /// Create a new writer with `compact` output and room for elems nested `expected_depth`
+    /// deep without reallocation. A `Vec<u8>` sink should be reserved by the caller.
+    pub fn compact_mode_with_capacity(writer: W, expected_depth: usize) -> XmlWriter<'a, W> {
+        let mut xml = Self::compact_mode(writer);
+        xml.stack.reserve(expected_depth);
+        xml.ns_stack.reserve(expected_depth);
+        xml
+    }
+
+    /// Create a new writer with `pretty` output, with capacity like `compact_mode_with_capacity`
+    pub fn pretty_mode_with_capacity(writer: W, expected_depth: usize) -> XmlWriter<'a, W> {
+        let mut xml = Self::compact_mode_with_capacity(writer, expected_depth);
+        xml.set_pretty_mode();
+        xml
+    }
+
+    /// Create a new writer with `very pretty` output, with capacity like `compact_mode_with_capacity`
+    pub fn very_pretty_mode_with_capacity(writer: W, expected_depth: usize) -> XmlWriter<'a, W> {
+        let mut xml = Self::compact_mode_with_capacity(writer, expected_depth);
+        xml.set_very_pretty_mode();
+        xml
+    }
+
+    /// Create a new writer with `pretty` output. Start tags, empty elems, comments and
+    /// processing instructions are put into own lines, text and CDATA are never indented,
+    /// so elems with only text content stay inline like `<a>hi</a>`.
+    /// Nodes before or after the root elem are put into own lines without indentation.
     pub fn pretty_mode(writer: W) -> XmlWriter<'a, W> {
-        XmlWriter {
-            stack: Vec::new(),
-            ns_stack: Vec::new(),
-            writer: Box::new(writer),
-            opened: false,
-            pretty: true,
-            namespace: None,
-            very_pretty: false,
-            children: false,
-            newline: false,
-        }
+        let mut xml = Self::compact_mode(writer);
+        xml.set_pretty_mode();
+        xml
     }
 
-    /// Create a new writer with `very pretty` output
+    /// Create a new writer with `very pretty` output. Line breaks are only written
+    /// in front of nodes, so a closed document ends with the `>` of the root elem.
     pub fn very_pretty_mode(writer: W) -> XmlWriter<'a, W> {
-        XmlWriter {
-            stack: Vec::new(),
-            ns_stack: Vec::new(),
-            writer: Box::new(writer),
-            opened: false,
-            pretty: true,
-            namespace: None,
-            very_pretty: true,
-            children: false,
-            newline: false,
-        }
+        let mut xml = Self::compact_mode(writer);
+        xml.set_very_pretty_mode();
+        xml
+    }
+
+    /// Create a new writer with `readable` output
+    pub fn readable_mode(writer: W) -> XmlWriter<'a, W> {
+        let mut xml = Self::compact_mode(writer);
+        xml.set_readable_mode();
+        xml
+    }
+
+    /// Create a new writer with `canonical` output, approximating the
+    /// [Canonical XML](https://www.w3.org/TR/xml-c14n) rules as used for xml signatures:
+    /// - no xml declaration and no whitespace is added
+    /// - namespace declarations and attrs are sorted, declarations by prefix first,
+    ///   then attrs by namespace uri and local name
+    /// - empty elems are written as start and end tag pairs like `<a></a>`
+    /// - `>` is not escaped in attr values, quotes are not escaped in text,
+    ///   `\r` is written as `&#xD;` and tabs and line breaks in attr values as references
+    /// - CDATA sections are written as escaped text
+    ///
+    /// Attrs written unescaped with `attr` are sorted, but kept as is.
+    pub fn canonical_mode(writer: W) -> XmlWriter<'a, W> {
+        let mut xml = Self::compact_mode(writer);
+        xml.set_canonical_mode();
+        xml
+    }
+
+    /// Create a new writer with `adaptive` output: the document is buffered and
+    /// written `pretty` by `finish`, if it fits into `threshold` bytes. Otherwise the
+    /// buffer is written `compact` as soon as the threshold is exceeded and the writer
+    /// continues in `compact` mode. Always call `finish` to get the buffered output written.
+    pub fn adaptive_mode(writer: W, threshold: usize) -> XmlWriter<'a, W> {
+        let mut xml = Self::pretty_mode(writer);
+        xml.adaptive = Some(Adaptive {
+            threshold,
+            buf: Vec::new(),
+            breaks: Vec::new(),
+            pretty_len: 0,
+        });
+        xml
     }
 
     /// Switch to `ccompact` mode
     pub fn set_compact_mode(&mut self) {
         self.pretty = false;
         self.very_pretty = false;
+        self.inline_text = false;
+        self.canonical = false;
     }
 
     /// Switch to `pretty` mode
     pub fn set_pretty_mode(&mut self) {
         self.pretty = true;
         self.very_pretty = false;
+        self.inline_text = false;
+        self.canonical = false;
     }
 
     /// Switch to `very pretty` mode
     pub fn set_very_pretty_mode(&mut self) {
         self.pretty = true;
         self.very_pretty = true;
+        self.inline_text = false;
+        self.canonical = false;
     }
 
+    /// Switch to `readable` mode, which is `very pretty` except that
+    /// elems with only text content are kept in a single line like `<name>value</name>`
+    pub fn set_readable_mode(&mut self) {
+        self.pretty = true;
+        self.very_pretty = true;
+        self.inline_text = true;
+        self.canonical = false;
+    }
 
-    /// Write the DTD
-    pub fn dtd(&mut self, encoding: &str) -> Result {
-        self.write("<?xml version=\"1.0\" encoding=\"")?;
-        self.write(encoding)?;
-        self.write("\" ?>\n")
+    /// In `very pretty` mode keep text which is the first child of its elem inline like
+    /// `<name>value</name>`, instead of in an own indented line. With `true` the
+    /// `very pretty` mode becomes the `readable` mode. The default is `false`,
+    /// except in `readable` mode. Switching the mode resets it.
+    pub fn set_inline_text_leaves(&mut self, inline: bool) {
+        self.inline_text = inline;
     }
 
-    fn indent(&mut self) -> Result {
-        let indent = self.stack.len();
+    /// Switch to `canonical` mode, see `canonical_mode`
+    pub fn set_canonical_mode(&mut self) {
+        self.pretty = false;
+        self.very_pretty = false;
+        self.inline_text = false;
+        self.canonical = true;
+    }
+
+    /// The current output mode, in `adaptive` mode `Pretty` until the threshold is exceeded
+    pub fn mode(&self) -> OutputMode {
         if self.very_pretty {
-            if self.newline {
-                self.write("\n")?;
-            } else {
-                self.newline = true;
-            }
-            for _ in 0..indent {
-                self.write("  ")?;
-            }
-        } else if self.pretty && !self.stack.is_empty() {
-            self.write("\n")?;
-            for _ in 0..(indent) {
-                self.write("  ")?;
-            }
+            OutputMode::VeryPretty
+        } else if self.pretty {
+            OutputMode::Pretty
+        } else {
+            OutputMode::Compact
         }
-        Ok(())
     }
 
-    /// Write a namespace prefix for the current element,
-    /// if there is one set
-    fn ns_prefix(&mut self, namespace: Option<&'a str>) -> Result {
-        if let Some(ns) = namespace {
-            self.write(ns)?;
-            self.write(":")?;
-        }
-        Ok(())
+    /// In strict mode misuse which would produce malformed xml is reported as `XmlError`
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
     }
 
-    /// Writes namespace declarations (xmlns:xx) into the currently open element
-    pub fn ns_decl(&mut self, ns_map: &Vec<(Option<&'a str>, &'a str)>) -> Result {
-        if !self.opened {
-            panic!(
-                "Attempted to write namespace decl to elem, when no elem was opened, stack {:?}",
-                self.stack
-            );
-        }
+    /// In safe mode the raw output methods `write`, `raw_block`, `text_unescaped` and the
+    /// unescaped `attr` return `XmlError::RawWriteForbidden` instead of writing unescaped content.
+    /// Namespace uris are escaped, a `]]>` in a CDATA is split into two sections and an
+    /// encoding which is no valid name is an `XmlError::InvalidEncoding`.
+    pub fn set_safe_mode(&mut self, safe: bool) {
+        self.safe = safe;
+    }
 
-        for item in ns_map {
-            let name = match item.0 {
-                Some(pre) => "xmlns:".to_string() + pre,
-                None => "xmlns".to_string(),
-            };
-            self.attr(&name, item.1)?;
-        }
-        Ok(())
+    /// Limit the nesting depth of elems, writing an elem nested deeper
+    /// is an `XmlError::MaxDepthExceeded`. `None` for no limit.
+    pub fn set_max_depth(&mut self, max_depth: Option<usize>) {
+        self.max_depth = max_depth;
     }
 
-    /// Write a self-closing element like <br/>
-    pub fn elem(&mut self, name: &str) -> Result {
-        self.close_elem()?;
-        self.indent()?;
-        self.write("<")?;
-        let ns = self.namespace;
-        self.ns_prefix(ns)?;
-        self.write(name)?;
-        self.write("/>")
+    /// In the pretty modes write elems containing text (mixed content) and everything
+    /// within them without indentation, so no whitespace is added to their content and
+    /// pretty printing a parsed document again gives the same output. Children written
+    /// before the first text of an elem are indented nevertheless. The default is `false`.
+    pub fn set_preserve_mixed_content(&mut self, preserve: bool) {
+        self.preserve_mixed = preserve;
     }
 
-    /// Write an element with inlined text (escaped)
-    pub fn elem_text(&mut self, name: &str, text: &str) -> Result {
-        self.close_elem()?;
-        self.indent()?;
-        self.write("<")?;
-        let ns = self.namespace;
-        self.ns_prefix(ns)?;
-        self.write(name)?;
-        self.write(">")?;
+    /// Set the `namespace` prefix elems are written with, e.g. right after
+    /// construction to write the whole document in one namespace
+    pub fn set_namespace(&mut self, namespace: Option<&'a str>) {
+        self.namespace = namespace;
+    }
 
-        self.escape(text, false)?;
+    /// Let `close` write an empty elem with the given name, if no root elem has been written,
+    /// so that even a document without any data is well-formed
+    pub fn set_empty_root(&mut self, name: Option<&'a str>) {
+        self.empty_root = name;
+    }
 
-        self.write("</")?;
-        self.write(name)?;
-        self.write(">")
+    /// Let `close` end the document with a line break after the root elem, once,
+    /// as many tools expect files to end with one. Nodes written after `close` take
+    /// the place of the line break, the next `close` ends the document with it again.
+    /// The default is `false`.
+    pub fn set_trailing_newline(&mut self, trailing_newline: bool) {
+        self.trailing_newline = trailing_newline;
     }
 
-    /// Begin an elem, make sure name contains only allowed chars
-    pub fn begin_elem(&mut self, name: &'a str) -> Result {
-        self.children = true;
-        self.close_elem()?;
-        // change previous elem to having children
-        if let Some(mut previous) = self.stack.pop() {
-            previous.1 = true;
-            self.stack.push(previous);
-        }
-        self.indent()?;
-        self.stack.push((name, false));
-        self.ns_stack.push(self.namespace);
-        self.write("<")?;
-        self.opened = true;
-        self.children = false;
-        // stderr().write_fmt(format_args!("\nbegin {}", name));
-        let ns = self.namespace;
-        self.ns_prefix(ns)?;
-        self.write(name)
+    /// Escape all non-ASCII chars as numeric character references (`&#xE9;`),
+    /// for consumers which can only handle 7-bit ASCII
+    pub fn set_ascii_only(&mut self, ascii_only: bool) {
+        self.ascii_only = ascii_only;
     }
 
-    /// Close an elem if open, do nothing otherwise
-    fn close_elem(&mut self) -> Result {
-        if self.opened {
-            if self.very_pretty && !self.children {
-                self.write("/>")?;
-            } else {
-                self.write(">")?;
-            }
-            self.opened = false;
-        }
-        Ok(())
+    /// Replace the chars in text content by the given strings, written as is, like
+    /// `('©', "&#169;")`. Chars with a meaning in markup are escaped as usual,
+    /// attr values are not affected. An empty list, the default, replaces nothing.
+    pub fn set_text_substitutions(&mut self, substitutions: &[(char, &str)]) {
+        self.text_substitutions = substitutions.iter().map(|&(c, s)| (c, s.to_string())).collect();
     }
 
-    /// End and elem
-    pub fn end_elem(&mut self) -> Result {
-        self.close_elem()?;
-        let ns = self.ns_stack.pop().unwrap_or_else(
-            || panic!("Attempted to close namespaced element without corresponding open namespace, stack {:?}", self.ns_stack)
-        );
-        match self.stack.pop() {
-            Some((name, children)) => {
-                if self.very_pretty {
-                    // elem without children have been self-closed
-                    if !children {
-                        return Ok(())
-                    }
-                    self.indent()?;
-                }
-                self.write("</")?;
-                self.ns_prefix(ns)?;
-                self.write(name)?;
-                self.write(">")?;
-                Ok(())
-            }
-            None => panic!(
-                "Attempted to close an elem, when none was open, stack {:?}",
-                self.stack
-            ),
-        }
+    /// Call `observer` with each node before it gets written, e.g. to trace where a
+    /// malformed document originates. Raw writes and declarations are not observed.
+    pub fn set_observer(&mut self, observer: Box<dyn FnMut(WriterEvent<'_>) + Send + Sync>) {
+        self.observer = Some(observer);
     }
 
-    /// Begin an empty elem
-    pub fn empty_elem(&mut self, name: &'a str) -> Result {
-        self.children = true;
-        self.close_elem()?;
-        // change previous elem to having children
-        if let Some(mut previous) = self.stack.pop() {
-            previous.1 = true;
-            self.stack.push(previous);
+    /// Tell the observer about a node about to be written
+    fn notify(&mut self, event: WriterEvent<'_>) {
+        if let Some(observer) = &mut self.observer {
+            observer(event);
         }
-        self.children = false;
-        self.indent()?;
-        self.write("<")?;
-        let ns = self.namespace;
-        self.ns_prefix(ns)?;
-        self.write(name)?;
-        self.write("/>")
     }
 
-    /// Write an attr, make sure name and value contain only allowed chars.
-    /// For an escaping version use `attr_esc`
-    pub fn attr(&mut self, name: &str, value: &str) -> Result {
-        if !self.opened {
-            panic!(
-                "Attempted to write attr to elem, when no elem was opened, stack {:?}",
-                self.stack
-            );
-        }
-        self.write(" ")?;
-        self.write(name)?;
-        self.write("=\"")?;
-        self.write(value)?;
-        self.write("\"")
+    /// Escape all chars not allowed by `filter` as numeric character references
+    /// (`&#xE9;`), for consumers restricted to a set of chars. Chars with a meaning
+    /// in markup are escaped as usual. The default allows every char.
+    pub fn set_char_filter(&mut self, filter: Box<dyn Fn(char) -> bool + Send + Sync>) {
+        self.char_filter = Some(filter);
     }
 
-    /// Write an attr, make sure name contains only allowed chars
-    pub fn attr_esc(&mut self, name: &str, value: &str) -> Result {
-        if !self.opened {
-            panic!(
-                "Attempted to write attr to elem, when no elem was opened, stack {:?}",
-                self.stack
-            );
-        }
-        self.write(" ")?;
-        self.escape(name, true)?;
-        self.write("=\"")?;
-        self.escape(value, false)?;
-        self.write("\"")
+    /// Keep entity and character references like `&amp;` or `&#169;` in text untouched,
+    /// only a bare `&` is escaped. For content which is partially escaped already.
+    pub fn set_preserve_entities(&mut self, preserve: bool) {
+        self.preserve_entities = preserve;
     }
 
-    /// Escape identifiers or text
-    fn escape(&mut self, text: &str, ident: bool) -> Result {
-        for c in text.chars() {
-            match c {
-                '"' => self.write("&quot;")?,
-                '\'' => self.write("&apos;")?,
-                '&' => self.write("&amp;")?,
-                '<' => self.write("&lt;")?,
-                '>' => self.write("&gt;")?,
-                '\\' if ident => self.write("\\\\")?,
-                _ => self.write_slice(c.encode_utf8(&mut [0; 4]).as_bytes())?,
-                // if let Some(len) =  {
-                //      try!(self.writer.write(&self.utf8[0..len])); ()
-                //  } else {
-                //      try!(; ()
-                //  }
-            };
-        }
-        Ok(())
+    /// Trim escaped attr values and collapse whitespace runs to a single space,
+    /// just like xml parsers normalize attr values
+    pub fn set_normalize_attr_whitespace(&mut self, normalize: bool) {
+        self.normalize_attr_whitespace = normalize;
     }
 
-    /// Write a text, escapes the text automatically
-    pub fn text(&mut self, text: &str) -> Result {
-        self.children = true;
-        self.close_elem()?;
-        // change previous elem to having children
-        if let Some(mut previous) = self.stack.pop() {
-            previous.1 = true;
-            self.stack.push(previous);
-        }
-        self.children = false;
-        if self.very_pretty {
-            self.indent()?;
-        }
-        self.escape(text, false)
+    /// Escape only what would break the markup: `&` and `<`, in attr values also `"`,
+    /// in text `>` only as part of `]]>`. Quotes in text and `>` in attr values stay as is.
+    pub fn set_minimal_escaping(&mut self, minimal: bool) {
+        self.minimal_escaping = minimal;
     }
 
-    /// Raw write, no escaping, no safety net, use at own risk
-    pub fn write(&mut self, text: &str) -> Result {
-        self.writer.write_all(text.as_bytes())?;
-        Ok(())
+    /// Write the attrs of each elem in the given order, like `["id", "xlink:href"]`,
+    /// the attrs not listed follow in the order they were written. The attrs are
+    /// buffered until the start tag is complete. An empty order disables the buffering.
+    pub fn set_attr_order(&mut self, order: &[&str]) {
+        self.attr_order = order.iter().map(|name| name.to_string()).collect();
     }
 
-    /// Raw write, no escaping, no safety net, use at own risk
-    fn write_slice(&mut self, slice: &[u8]) -> Result {
-        self.writer.write_all(slice)?;
-        Ok(())
+    /// In the pretty modes wrap text written with `text` at spaces, so lines do not exceed
+    /// the given column if possible. The spaces wrapped at are replaced by the line break.
+    /// In `adaptive` mode text is not wrapped, as the line breaks may be dropped.
+    pub fn set_wrap_text_at(&mut self, width: Option<usize>) {
+        self.wrap_text_at = width;
     }
 
-    /// Write a CDATA
-    pub fn cdata(&mut self, cdata: &str) -> Result {
-        self.children = true;
-        self.close_elem()?;
-        // change previous elem to having children
-        if let Some(mut previous) = self.stack.pop() {
-            previous.1 = true;
-            self.stack.push(previous);
-        }
-        if self.very_pretty {
-            self.indent()?;
-        }
-        self.children = false;
-        self.write("<![CDATA[")?;
-        self.write(cdata)?;
-        self.write("]]>")
+    /// In html mode text and attr values are escaped by the rules of HTML serialization:
+    /// `&`, `<`, `>` and no-break spaces in text, `&`, `<`, the quote char and no-break
+    /// spaces in attr values, `<` to keep the output well-formed xml too. Apostrophes are
+    /// never written as `&apos;`, which HTML 4 does not know.
+    pub fn set_html_mode(&mut self, html: bool) {
+        self.html_mode = html;
     }
 
-    /// Write a comment
-    pub fn comment(&mut self, comment: &str) -> Result {
-        self.children = true;
-        self.close_elem()?;
-        // change previous elem to having children
-        if let Some(mut previous) = self.stack.pop() {
-            previous.1 = true;
-            self.stack.push(previous);
-        }
-        self.indent()?;
-        self.children = false;
-        self.write("<!-- ")?;
-        self.escape(comment, false)?;
-        self.write(" -->")
+    /// Delimit attr values, namespace declarations and the values of the xml declaration
+    /// and entity declarations with the given quote char, the quote char is escaped in values.
+    /// `canonical` mode always uses double quotes.
+    pub fn set_quote(&mut self, quote: Quote) {
+        self.quote = quote;
     }
 
-    /// Close all open elems
-    pub fn close(&mut self) -> Result {
-        for _ in 0..self.stack.len() {
-            self.end_elem()?;
+    /// Transform element and attribute names to the given case on write,
+    /// e.g. `NameCase::Lower` writes `<div>` for `"DIV"`. Prefixes are written as given.
+    pub fn set_name_case(&mut self, name_case: NameCase) {
+        self.name_case = name_case;
+    }
+
+    /// With `false` elems without content are never self-closed but written like `<x></x>`,
+    /// also by `empty_elem`. Combined with `very pretty` mode this gives pretty nesting with
+    /// the end tags of elems with children in own lines, as some SOAP toolkits demand.
+    pub fn set_self_close(&mut self, self_close: bool) {
+        self.self_close = self_close;
+    }
+
+    /// Write the root elem as `<root></root>` even without content,
+    /// while the elems below may still be self-closed
+    pub fn set_explicit_close_root(&mut self, explicit: bool) {
+        self.explicit_close_root = explicit;
+    }
+
+    /// Write elems declaring a namespace like `<a xmlns:x="urn:x"></a>` even without
+    /// content, for consumers choking on self-closed elems with namespace declarations
+    pub fn set_explicit_close_ns(&mut self, explicit: bool) {
+        self.explicit_close_ns = explicit;
+    }
+
+    /// Put a space before the `/>` of self-closing elems like `<br />`,
+    /// always or only if the elem has attrs like `<input type="x" />`
+    pub fn set_self_close_space(&mut self, space: SelfCloseSpace) {
+        self.self_close_space = space;
+    }
+
+    /// In pretty mode put each attr after the first of a start tag on an own line,
+    /// aligned under the elem name, under the first attr or at a fixed indent.
+    /// The alignment follows the output position, which `capture` does not count,
+    /// in `adaptive` mode the attrs stay on one line.
+    pub fn set_attr_wrap(&mut self, style: Option<AttrWrapStyle>) {
+        self.attr_wrap = style;
+    }
+
+    /// In `very pretty` mode an elem containing only comments is written like
+    /// `<x><!-- c --></x>`, with `false` it is self-closed and its comments
+    /// are written after it, like `<x/><!-- c -->`. The default is `true`.
+    pub fn set_comments_count_as_children(&mut self, count: bool) {
+        self.comments_count_as_children = count;
+    }
+
+    /// With `true` `attrs_from_json` skips null, object and array fields,
+    /// by default they are an error. The top level value has to be an object anyway.
+    #[cfg(feature = "json")]
+    pub fn set_json_skip_nested(&mut self, skip: bool) {
+        self.json_skip_nested = skip;
+    }
+
+    /// Write a byte order mark, which may precede the DTD in strict mode
+    pub fn bom(&mut self) -> Result {
+        self.write_str("\u{FEFF}")
+    }
+
+    /// Write the DTD, in strict mode it has to be the first thing written
+    /// after an optional BOM.
+    /// In `canonical` mode nothing is written.
+    pub fn dtd(&mut self, encoding: &str) -> Result {
+        self.check_encoding(encoding)?;
+        self.write_decl(|xml| xml.write_str(encoding))
+    }
+
+    /// In safe mode check the encoding is a name like `UTF-8` or `ISO-8859-1`
+    fn check_encoding(&self, encoding: &str) -> Result {
+        let mut bytes = encoding.bytes();
+        let valid = bytes.next().is_some_and(|b| b.is_ascii_alphabetic())
+            && bytes.all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b));
+        if self.safe && !valid {
+            return Err(XmlError::InvalidEncoding(encoding.to_string()).into());
         }
         Ok(())
     }
 
-    /// Flush the underlying Writer
-    pub fn flush(&mut self) -> Result {
-        self.writer.flush()
+    /// Write the xml declaration with the encoding written by `write_encoding`,
+    /// unless in `canonical` mode
+    fn write_decl(&mut self, write_encoding: impl FnOnce(&mut Self) -> Result) -> Result {
+        if self.strict && self.wrote_anything {
+            return Err(XmlError::DeclarationNotFirst.into());
+        }
+        if self.canonical {
+            return Ok(());
+        }
+        self.write_str("<?xml version=")?;
+        self.write_quote()?;
+        self.write_str("1.0")?;
+        self.write_quote()?;
+        self.write_str(" encoding=")?;
+        self.write_quote()?;
+        write_encoding(self)?;
+        self.write_quote()?;
+        self.write_str(" ?>\n")
     }
 
-    /// Consume the XmlWriter and return the inner Writer
-    pub fn into_inner(self) -> W {
-        *self.writer
+    /// Begin a DOCTYPE with an internal subset like `<!DOCTYPE name [`,
+    /// to write declarations with `entity_decl` until `end_doctype`
+    pub fn begin_doctype(&mut self, name: &str) -> Result {
+        Self::check_name(name)?;
+        self.begin_child(true)?;
+        self.indent()?;
+        self.write_str("<!DOCTYPE ")?;
+        self.write_str(name)?;
+        self.write_str(" [")?;
+        self.in_subset = true;
+        Ok(())
     }
-}
 
-#[allow(unused_must_use)]
-#[cfg(test)]
-mod tests {
-    use super::XmlWriter;
-    use std::str;
+    /// Declare an entity like `<!ENTITY name "value">` in the internal DOCTYPE subset,
+    /// the value is escaped, so the entity is replaced by exactly that text
+    pub fn entity_decl(&mut self, name: &str, value: &str) -> Result {
+        if !self.in_subset {
+            return Err(XmlError::OutsideDoctypeSubset.into());
+        }
+        Self::check_name(name)?;
+        if self.pretty {
+            self.line_break(1)?;
+        }
+        self.write_str("<!ENTITY ")?;
+        self.write_str(name)?;
+        self.write_str(" ")?;
+        self.write_quote()?;
+        // a `%` would start a parameter entity reference
+        for (i, part) in value.split('%').enumerate() {
+            if i > 0 {
+                self.char_ref('%')?;
+            }
+            self.escape(part, true)?;
+        }
+        self.write_quote()?;
+        self.write_str(">")
+    }
+
+    /// End the internal DOCTYPE subset begun with `begin_doctype`
+    pub fn end_doctype(&mut self) -> Result {
+        if !self.in_subset {
+            return Err(XmlError::OutsideDoctypeSubset.into());
+        }
+        self.in_subset = false;
+        if self.pretty {
+            self.line_break(0)?;
+        }
+        self.write_str("]>")
+    }
+
+    fn indent(&mut self) -> Result {
+        let indent = self.stack.len();
+        // a node following the trailing line break starts on the line it began
+        if self.wrote_trailing_newline && self.pretty && indent == 0 {
+            self.wrote_trailing_newline = false;
+            return Ok(());
+        }
+        if self.mixed_depth.is_some_and(|depth| depth <= indent) {
+            return Ok(());
+        }
+        if self.very_pretty {
+            if self.newline {
+                return self.line_break(indent);
+            }
+            self.newline = true;
+            for _ in 0..indent {
+                self.write_str("  ")?;
+            }
+        } else if self.pretty {
+            // at the document root only nodes following other nodes start a new line
+            if !self.stack.is_empty() || self.newline {
+                return self.line_break(indent);
+            }
+            self.newline = true;
+        }
+        Ok(())
+    }
+
+    /// Start a new line indented to `depth`, in `adaptive` mode the
+    /// line break is only recorded as long as the output is buffered
+    fn line_break(&mut self, depth: usize) -> Result {
+        if self.capture.is_none()
+            && let Some(adaptive) = &mut self.adaptive
+        {
+            adaptive.breaks.push((adaptive.buf.len(), depth));
+            adaptive.pretty_len += 1 + 2 * depth;
+            self.line += 1;
+            self.column = 1 + 2 * depth;
+            return self.check_threshold();
+        }
+        self.write_str("\n")?;
+        for _ in 0..depth {
+            self.write_str("  ")?;
+        }
+        Ok(())
+    }
+
+    /// In `adaptive` mode write the buffered output `compact` and continue in `compact` mode,
+    /// once the output would exceed the threshold when pretty printed
+    fn check_threshold(&mut self) -> Result {
+        if self.adaptive.as_ref().is_some_and(|adaptive| adaptive.pretty_len > adaptive.threshold)
+            && let Some(adaptive) = self.adaptive.take()
+        {
+            self.set_compact_mode();
+            self.writer.write_all(&adaptive.buf)?;
+        }
+        Ok(())
+    }
+
+    /// The namespace prefix of an elem `name`, `None` for a qualified name like
+    /// `soap:Envelope` which already has one
+    fn elem_namespace(&self, name: &str) -> Option<&'a str> {
+        if name.contains(':') { None } else { self.namespace }
+    }
+
+    /// Write a namespace prefix for the current element,
+    /// if there is one set
+    fn ns_prefix(&mut self, namespace: Option<&'a str>) -> Result {
+        if let Some(ns) = namespace {
+            self.write_str(ns)?;
+            self.write_str(":")?;
+        }
+        Ok(())
+    }
+
+    /// Writes namespace declarations (xmlns:xx) into the currently open element
+    pub fn ns_decl(&mut self, ns_map: &Vec<(Option<&'a str>, &'a str)>) -> Result {
+        self.check_opened()?;
+        for &(prefix, uri) in ns_map {
+            self.declare_ns(prefix, uri)?;
+        }
+        Ok(())
+    }
+
+    /// Write a single namespace declaration and bind it to the currently open element
+    fn declare_ns(&mut self, prefix: Option<&'a str>, uri: &'a str) -> Result {
+        let name = match prefix {
+            Some(pre) => (Some("xmlns"), pre),
+            None => (None, "xmlns"),
+        };
+        self.sorted_attr(name, |xml| {
+            xml.write_str("xmlns")?;
+            if let Some(pre) = prefix {
+                xml.write_str(":")?;
+                xml.write_str(pre)?;
+            }
+            xml.write_str("=")?;
+            xml.write_quote()?;
+            if xml.safe {
+                xml.escape_attr_value(uri)?;
+            } else {
+                xml.write_str(uri)?;
+            }
+            xml.write_quote()
+        })?;
+        self.bindings.push((self.stack.len(), prefix, uri));
+        if let Some(elem) = self.stack.last_mut() {
+            elem.declares_ns = true;
+        }
+        Ok(())
+    }
+
+    /// Forget the namespace bindings of the elem at `depth` and restore the
+    /// `namespace` that was active before it was opened
+    fn pop_scope(&mut self, depth: usize) {
+        while let Some(&(d, _, _)) = self.bindings.last() {
+            if d < depth {
+                break;
+            }
+            self.bindings.pop();
+        }
+        if let Some(&(d, namespace)) = self.ns_restore.last()
+            && d >= depth
+        {
+            self.namespace = namespace;
+            self.ns_restore.pop();
+        }
+    }
+
+    /// End elems until the innermost open elem named `name` is the current elem,
+    /// or until it is ended too if `inclusive`
+    pub fn close_to(&mut self, name: &str, inclusive: bool) -> Result {
+        let Some(pos) = self.stack.iter().rposition(|elem| elem.name == name) else {
+            return Err(XmlError::NoSuchOpenElement(name.to_string()).into());
+        };
+        let depth = if inclusive { pos } else { pos + 1 };
+        while self.stack.len() > depth {
+            self.end_elem()?;
+        }
+        Ok(())
+    }
+
+    /// Write a self-closing element like <br/>, the same as `empty_elem`
+    pub fn elem(&mut self, name: &str) -> Result {
+        self.elem_from_map(name, std::iter::empty::<(&str, &str)>())
+    }
+
+    /// Write an element with inlined text (escaped)
+    pub fn elem_text(&mut self, name: &str, text: &str) -> Result {
+        self.elem_text_attrs(name, &[], text)
+    }
+
+    /// Write an element with inlined text (escaped) if there is a text, nothing otherwise
+    pub fn elem_text_opt(&mut self, name: &str, text: Option<impl AsRef<str>>) -> Result {
+        match text {
+            Some(text) => self.elem_text(name, text.as_ref()),
+            None => Ok(()),
+        }
+    }
+
+    /// Write an elem `item_name` with inlined text (escaped) for each of `items`,
+    /// like `<tag>a</tag><tag>b</tag>` into the open elem
+    pub fn text_list<I>(&mut self, item_name: &str, items: I) -> Result
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        for item in items {
+            self.elem_text(item_name, item.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Write the elem `wrapper` containing a `text_list`,
+    /// like `<tags><tag>a</tag><tag>b</tag></tags>`
+    pub fn wrapped_text_list<I>(&mut self, wrapper: &'a str, item_name: &str, items: I) -> Result
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        self.begin_elem(wrapper)?;
+        self.text_list(item_name, items)?;
+        self.end_elem()
+    }
+
+    /// Write an elem with the content written by `f` if `cond` holds, nothing otherwise
+    pub fn elem_opt<F>(&mut self, name: &'a str, cond: bool, f: F) -> Result
+    where
+        F: FnOnce(&mut Self) -> Result,
+    {
+        if !cond {
+            return Ok(());
+        }
+        self.begin_elem(name)?;
+        f(self)?;
+        self.end_elem()
+    }
+
+    /// Write an elem with the content written by `then` if `cond` holds,
+    /// otherwise write whatever `otherwise` writes instead of the elem
+    pub fn element_or<F, G>(&mut self, cond: bool, name: &'a str, then: F, otherwise: G) -> Result
+    where
+        F: FnOnce(&mut Self) -> Result,
+        G: FnOnce(&mut Self) -> Result,
+    {
+        if !cond {
+            return otherwise(self);
+        }
+        self.begin_elem(name)?;
+        then(self)?;
+        self.end_elem()
+    }
+
+    /// Write whatever `f` writes if `cond` holds, nothing otherwise
+    pub fn when<F>(&mut self, cond: bool, f: F) -> Result
+    where
+        F: FnOnce(&mut Self) -> Result,
+    {
+        if cond { f(self) } else { Ok(()) }
+    }
+
+    /// Write an elem with the content written by `f` in compact mode, like a dense data
+    /// block within a pretty document. The elem itself is placed as usual, the previous
+    /// mode is restored after its end tag.
+    pub fn begin_compact<F>(&mut self, name: &'a str, f: F) -> Result
+    where
+        F: FnOnce(&mut Self) -> Result,
+    {
+        self.begin_elem(name)?;
+        let modes = (self.pretty, self.very_pretty);
+        (self.pretty, self.very_pretty) = (false, false);
+        let result = f(self).and_then(|_| self.end_elem());
+        (self.pretty, self.very_pretty) = modes;
+        result
+    }
+
+    /// Write an element with escaped attrs and inlined text (escaped) like
+    /// `<a href="x">link</a>`, kept in a single line in the pretty modes
+    pub fn elem_text_attrs(&mut self, name: &str, attrs: &[(&str, &str)], text: &str) -> Result {
+        self.leaf_elem(name, attrs, text, FieldPolicy::Escape)
+    }
+
+    /// Write an element with inlined text like `elem_text`, escaped or in a CDATA section
+    /// as the `policy` of the field says, e.g. taken from schema metadata.
+    /// A `]]>` in a CDATA section is split like `]]]]><![CDATA[>`.
+    pub fn elem_text_policy(&mut self, name: &str, text: &str, policy: FieldPolicy) -> Result {
+        self.leaf_elem(name, &[], text, policy)
+    }
+
+    /// Write an element with escaped attrs and inlined text as the `policy` says
+    fn leaf_elem(&mut self, name: &str, attrs: &[(&str, &str)], text: &str, policy: FieldPolicy) -> Result {
+        self.check_depth()?;
+        // canonical xml has no CDATA sections
+        let policy = if self.canonical { FieldPolicy::Escape } else { policy };
+        self.last_node = Some(NodeKind::EndTag);
+        self.notify(WriterEvent::Open(name));
+        self.begin_child(true)?;
+        self.indent()?;
+        self.wrote_root = true;
+        self.open_tag()?;
+        let ns = self.elem_namespace(name);
+        self.ns_prefix(ns)?;
+        self.write_name(name)?;
+        for &(name, value) in attrs {
+            self.write_attr_esc(name, value)?;
+        }
+        self.write_pending_attrs()?;
+        self.write_str(">")?;
+
+        match policy {
+            FieldPolicy::Escape => {
+                self.notify(WriterEvent::Text);
+                self.escape_text(text)?;
+            }
+            FieldPolicy::CData => {
+                self.notify(WriterEvent::CData);
+                self.write_str("<![CDATA[")?;
+                self.write_cdata_content(text)?;
+                self.write_str("]]>")?;
+            }
+        }
+
+        self.notify(WriterEvent::Close(name));
+        self.write_str("</")?;
+        self.ns_prefix(ns)?;
+        self.write_name(name)?;
+        self.write_str(">")
+    }
+
+    /// Write the `<` of a start tag, which has no attrs yet
+    fn open_tag(&mut self) -> Result {
+        self.attrs_written = false;
+        self.write_str("<")?;
+        self.name_column = self.column;
+        Ok(())
+    }
+
+    /// Begin an elem, make sure name contains only allowed chars
+    pub fn begin_elem(&mut self, name: &'a str) -> Result {
+        self.check_depth()?;
+        self.last_node = Some(NodeKind::StartTag);
+        self.notify(WriterEvent::Open(name));
+        self.begin_child(true)?;
+        self.indent()?;
+        self.stack.push(Elem {
+            name,
+            children: false,
+            nested: false,
+            declares_ns: false,
+        });
+        let ns = self.elem_namespace(name);
+        self.ns_stack.push(ns);
+        self.wrote_root = true;
+        self.open_tag()?;
+        self.opened = true;
+        // stderr().write_fmt(format_args!("\nbegin {}", name));
+        self.ns_prefix(ns)?;
+        self.write_name(name)
+    }
+
+    /// Begin an elem which declares `uri` as the default namespace of its subtree.
+    /// The elem and its descendants are written without a prefix, the previous
+    /// `namespace` is restored when the elem gets closed.
+    pub fn begin_elem_default_ns(&mut self, name: &'a str, uri: &'a str) -> Result {
+        self.begin_elem_ns(None, name, uri)
+    }
+
+    /// Begin an elem `prefix:local` which declares `uri` for the prefix, or as the
+    /// default namespace without prefix. The elem and its descendants are written with
+    /// the prefix, the previous `namespace` is restored when the elem gets closed.
+    pub fn begin_elem_ns(&mut self, prefix: Option<&'a str>, local: &'a str, uri: &'a str) -> Result {
+        if let Some(prefix) = prefix {
+            Self::check_name(prefix)?;
+        }
+        let previous = self.namespace;
+        self.namespace = prefix;
+        if let Err(err) = self.begin_elem(local) {
+            self.namespace = previous;
+            return Err(err);
+        }
+        self.ns_restore.push((self.stack.len(), previous));
+        self.declare_ns(prefix, uri)
+    }
+
+    /// Begin an elem `prefix:local`, or just `local` without prefix, regardless of `namespace`.
+    /// The end tag gets the same prefix. The prefix is not declared, see `begin_elem_ns`.
+    pub fn begin_qname(&mut self, prefix: Option<&'a str>, local: &'a str) -> Result {
+        if let Some(prefix) = prefix {
+            Self::check_ncname(prefix)?;
+        }
+        Self::check_ncname(local)?;
+        let namespace = std::mem::replace(&mut self.namespace, prefix);
+        let result = self.begin_elem(local);
+        self.namespace = namespace;
+        result
+    }
+
+    /// Begin an elem `local` in the namespace `uri`, with the prefix `ctx` binds to it.
+    /// The binding is declared on the elem, unless the open elems declare it already.
+    pub fn begin_elem_ctx(&mut self, ctx: &NamespaceContext<'a>, uri: &'a str, local: &'a str) -> Result {
+        let prefix = ctx.prefix(uri).ok_or_else(|| XmlError::UnboundNamespace(uri.to_string()))?;
+        self.begin_qname(prefix, local)?;
+        self.declare_missing_ns(prefix, uri)
+    }
+
+    /// Write an attr `local` in the namespace `uri` with an escaped value, with the prefix
+    /// `ctx` binds to it, as attrs can not be in the default namespace. The binding is
+    /// declared on the open elem, unless the open elems declare it already.
+    pub fn attr_ctx(&mut self, ctx: &NamespaceContext<'a>, uri: &'a str, local: &str, value: &str) -> Result {
+        self.check_opened()?;
+        let prefix = ctx.attr_prefix(uri).ok_or_else(|| XmlError::UnboundNamespace(uri.to_string()))?;
+        self.declare_missing_ns(Some(prefix), uri)?;
+        self.attr_qname(prefix, local, value)
+    }
+
+    /// Declare `prefix` for `uri` on the open elem, unless it is bound to it already
+    fn declare_missing_ns(&mut self, prefix: Option<&'a str>, uri: &'a str) -> Result {
+        let bound = self.bindings.iter().rev().find(|&&(_, bound, _)| bound == prefix);
+        if bound.is_some_and(|&(_, _, bound)| bound == uri) {
+            return Ok(());
+        }
+        self.declare_ns(prefix, uri)
+    }
+
+    /// Check a new elem would not be nested deeper than `max_depth`
+    fn check_depth(&self) -> Result {
+        if let Some(max_depth) = self.max_depth
+            && self.stack.len() >= max_depth
+        {
+            return Err(XmlError::MaxDepthExceeded(max_depth).into());
+        }
+        Ok(())
+    }
+
+    /// Complete the start tag of the current elem and mark it as having children,
+    /// `nested` if the new child goes into an own line
+    fn begin_child(&mut self, nested: bool) -> Result {
+        // a child keeps the elem from being self-closed, so its comments go inside
+        for comment in std::mem::take(&mut self.deferred_comments) {
+            self.write_comment(&comment)?;
+        }
+        self.children = true;
+        self.close_elem()?;
+        self.children = false;
+        if let Some(parent) = self.stack.last_mut() {
+            parent.children = true;
+            parent.nested |= nested;
+        }
+        Ok(())
+    }
+
+    /// Complete a pending start tag, so no half-written tag is left in the output.
+    /// The elem can not be self-closed anymore, so it is marked as having children.
+    fn complete_start_tag(&mut self) -> Result {
+        if self.opened {
+            self.begin_child(false)?;
+        }
+        Ok(())
+    }
+
+    /// `true` if character data goes into an own line
+    fn text_nested(&self) -> bool {
+        self.very_pretty && (!self.inline_text || self.stack.last().is_some_and(|elem| elem.nested))
+    }
+
+    /// Close an elem if open, do nothing otherwise
+    fn close_elem(&mut self) -> Result {
+        if self.opened {
+            self.write_pending_attrs()?;
+            if self.very_pretty && !self.children && !self.explicit_close_open() {
+                self.write_self_close()?;
+            } else {
+                self.write_str(">")?;
+            }
+            self.opened = false;
+        }
+        Ok(())
+    }
+
+    /// End and elem
+    pub fn end_elem(&mut self) -> Result {
+        self.end_elem_name().map(|_| ())
+    }
+
+    /// End an elem and return the name it was opened with
+    pub fn end_elem_name(&mut self) -> io::Result<&'a str> {
+        self.close_elem()?;
+        self.pop_scope(self.stack.len());
+        let ns = self.ns_stack.pop().unwrap_or_else(
+            || panic!("Attempted to close namespaced element without corresponding open namespace, stack {:?}", self.ns_stack)
+        );
+        let mixed = self.mixed_depth.is_some_and(|depth| depth <= self.stack.len());
+        if self.mixed_depth == Some(self.stack.len()) {
+            self.mixed_depth = None;
+        }
+        match self.stack.pop() {
+            Some(elem) => {
+                self.last_node = Some(NodeKind::EndTag);
+                self.notify(WriterEvent::Close(elem.name));
+                if self.very_pretty {
+                    // elem without children have been self-closed
+                    if !elem.children && !self.explicit_close(self.stack.len(), elem.declares_ns) {
+                        for comment in std::mem::take(&mut self.deferred_comments) {
+                            self.write_comment(&comment)?;
+                        }
+                        return Ok(elem.name)
+                    }
+                    if !mixed && elem.children && (elem.nested || !self.inline_text) {
+                        self.indent()?;
+                    }
+                }
+                self.write_str("</")?;
+                self.ns_prefix(ns)?;
+                self.write_name(elem.name)?;
+                self.write_str(">")?;
+                Ok(elem.name)
+            }
+            None => panic!(
+                "Attempted to close an elem, when none was open, stack {:?}",
+                self.stack
+            ),
+        }
+    }
+
+    /// Write a self-closing elem like <br/>, the same as `elem`.
+    /// Like every child it keeps the parent from being self-closed.
+    pub fn empty_elem(&mut self, name: &str) -> Result {
+        self.elem_from_map(name, std::iter::empty::<(&str, &str)>())
+    }
+
+    /// Write a self-closing elem with all entries of `attrs` as escaped attrs,
+    /// use an ordered map like `BTreeMap` for a deterministic order
+    pub fn elem_from_map<K, V>(&mut self, name: &str, attrs: impl IntoIterator<Item = (K, V)>) -> Result
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.check_depth()?;
+        self.last_node = Some(NodeKind::Empty);
+        self.notify(WriterEvent::Empty(name));
+        self.begin_child(true)?;
+        self.indent()?;
+        self.wrote_root = true;
+        self.open_tag()?;
+        let ns = self.elem_namespace(name);
+        self.ns_prefix(ns)?;
+        self.write_name(name)?;
+        for (name, value) in attrs {
+            self.write_attr_esc(name.as_ref(), value.as_ref())?;
+        }
+        self.end_empty_elem(ns, name)
+    }
+
+    /// End the start tag of an elem without content, self-closing unless `explicit_close`
+    fn end_empty_elem(&mut self, ns: Option<&'a str>, name: &str) -> Result {
+        self.write_pending_attrs()?;
+        if !self.explicit_close(self.stack.len(), false) {
+            return self.write_self_close();
+        }
+        self.write_str("></")?;
+        self.ns_prefix(ns)?;
+        self.write_name(name)?;
+        self.write_str(">")
+    }
+
+    /// Write an elem like `<entry key="k">v</entry>` for each pair of `map`,
+    /// with the key as escaped attr `key_attr` and the value as escaped text.
+    /// Use an ordered map like `BTreeMap` for a deterministic order.
+    pub fn map_entries<K, V>(&mut self, elem_name: &str, key_attr: &str, map: impl IntoIterator<Item = (K, V)>) -> Result
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        for (key, value) in map {
+            self.elem_text_attrs(elem_name, &[(key_attr, key.as_ref())], value.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Write an attr, make sure name and value contain only allowed chars.
+    /// The value is written as is, like with `attr_raw`. Prefer `attr_raw` for values
+    /// escaped already and `attr_esc` for any other, to make the escaping obvious.
+    /// However attrs and namespace declarations are added, each is preceded by exactly
+    /// one space and none is written before the `>` of the start tag.
+    pub fn attr(&mut self, name: impl AttrName, value: &str) -> Result {
+        self.attr_raw(name, value)
+    }
+
+    /// Write an attr with a value which is escaped already, like `&amp;` from a cache,
+    /// as is. This is the unescaped entry point, forbidden in safe mode.
+    pub fn attr_raw(&mut self, name: impl AttrName, value: &str) -> Result {
+        self.check_opened()?;
+        self.check_raw_allowed()?;
+        let name = name.as_str();
+        self.sorted_attr(Self::split_name(name), |xml| {
+            xml.write_name(name)?;
+            xml.write_str("=")?;
+            xml.write_quote()?;
+            xml.write_str(value)?;
+            xml.write_quote()
+        })
+    }
+
+    /// Write an attr with a value which is escaped already as is, the same as `attr_raw`
+    pub fn attr_pre_escaped(&mut self, name: impl AttrName, value: &str) -> Result {
+        self.attr_raw(name, value)
+    }
+
+    /// Write an attr with the value written by `f` into a `fmt::Write`, which escapes
+    /// as it goes, e.g. `xml.attr_with("size", |w| write!(w, "{}x{}", 3, 4))`.
+    /// Whitespace in the value is not normalized.
+    pub fn attr_with<F>(&mut self, name: &str, f: F) -> Result
+    where
+        F: FnOnce(&mut dyn fmt::Write) -> fmt::Result,
+    {
+        self.check_opened()?;
+        Self::check_name(name)?;
+        self.sorted_attr(Self::split_name(name), |xml| {
+            xml.write_name(name)?;
+            xml.write_str("=")?;
+            xml.write_quote()?;
+            let mut value = Escaping { xml: &mut *xml, attr: true, err: None };
+            let result = f(&mut value);
+            if let Some(err) = value.err {
+                return Err(err);
+            }
+            // the value is closed even if `f` failed, to keep the markup intact
+            xml.write_quote()?;
+            result.map_err(|_| io::Error::other("formatting the attr value failed"))
+        })
+    }
+
+    /// Write an attr with the space separated names of the flags which are `true`, like
+    /// `rel="nofollow noopener"`, nothing if none is. The names are escaped.
+    pub fn attr_flags(&mut self, name: &str, flags: &[(&str, bool)]) -> Result {
+        if !flags.iter().any(|&(_, set)| set) {
+            return Ok(());
+        }
+        self.attr_with(name, |w| {
+            let names = flags.iter().filter(|&&(_, set)| set).map(|&(flag, _)| flag);
+            for (i, flag) in names.enumerate() {
+                if i > 0 {
+                    w.write_str(" ")?;
+                }
+                w.write_str(flag)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Write a timestamp attr formatted by its `Display` impl, without allocating,
+    /// e.g. `xml.attr_datetime("at", &dt.format("%+"))` with a `chrono::DateTime`
+    /// for an RFC 3339 string.
+    pub fn attr_datetime(&mut self, name: &str, dt: &impl fmt::Display) -> Result {
+        self.attr_with(name, |w| write!(w, "{}", dt))
+    }
+
+    /// Write all attrs of the set to the open elem, just like calling `attr` for each
+    pub fn apply_attrs(&mut self, attrs: &AttrSet) -> Result {
+        for (name, value) in attrs.iter() {
+            self.attr(name, value)?;
+        }
+        Ok(())
+    }
+
+    /// Write all entries of `attrs` as escaped attrs to the open elem, in iteration order.
+    /// An insertion ordered map like `indexmap::IndexMap` or an ordered map like `BTreeMap`
+    /// gives a deterministic order, unlike `HashMap`.
+    pub fn attrs<K, V>(&mut self, attrs: impl IntoIterator<Item = (K, V)>) -> Result
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        for (name, value) in attrs {
+            self.attr_esc(name.as_ref(), value.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Write an attr with an escaped value, the name must not contain whitespace
+    /// or chars with a meaning in markup (`"'&<>=/`), as names can not be escaped
+    pub fn attr_esc(&mut self, name: impl AttrName, value: &str) -> Result {
+        self.check_opened()?;
+        self.write_attr_esc(name.as_str(), value)
+    }
+
+    /// Write the fields of a JSON object like `{"id": 7, "name": "a & b"}` as escaped attrs,
+    /// numbers as written and bools as `true` or `false`, see `set_json_skip_nested`.
+    /// Malformed json is reported as `XmlError::InvalidJson` and nothing is written then.
+    #[cfg(feature = "json")]
+    pub fn attrs_from_json(&mut self, json: &str) -> Result {
+        self.check_opened()?;
+        let skip_nested = self.json_skip_nested;
+        json::for_each_field(json, skip_nested, |key, _| Self::check_name(key))?;
+        json::for_each_field(json, skip_nested, |key, value| self.write_attr_esc(key, value))
+    }
+
+    /// Write a namespaced attr like `xlink:href` with an escaped value.
+    /// In strict mode the prefix has to be declared by the open elems.
+    pub fn attr_qname(&mut self, prefix: &'a str, local: &str, value: &str) -> Result {
+        self.check_opened()?;
+        Self::check_name(prefix)?;
+        Self::check_name(local)?;
+        if self.strict && !self.is_declared(prefix) {
+            return Err(XmlError::UndeclaredPrefix(prefix.to_string()).into());
+        }
+        self.sorted_attr((Some(prefix), local), |xml| {
+            xml.write_str(prefix)?;
+            xml.write_str(":")?;
+            xml.write_name(local)?;
+            xml.write_str("=")?;
+            xml.write_quote()?;
+            xml.escape_attr_value(value)?;
+            xml.write_quote()
+        })
+    }
+
+    /// Check there is an open start tag to write attrs to
+    fn check_opened(&self) -> Result {
+        if !self.opened {
+            return Err(XmlError::AttrOutsideElement.into());
+        }
+        Ok(())
+    }
+
+    /// `true` if the prefix is declared by one of the open elems, `xml` is always declared
+    fn is_declared(&self, prefix: &str) -> bool {
+        prefix == "xml" || self.bindings.iter().any(|&(_, bound, _)| bound == Some(prefix))
+    }
+
+    /// Write an attr with an escaped value, without checking for an open elem
+    fn write_attr_esc(&mut self, name: &str, value: &str) -> Result {
+        Self::check_name(name)?;
+        self.sorted_attr(Self::split_name(name), |xml| {
+            xml.write_name(name)?;
+            xml.write_str("=")?;
+            xml.write_quote()?;
+            xml.escape_attr_value(value)?;
+            xml.write_quote()
+        })
+    }
+
+    /// `true` if an elem without content at `depth` has to be written as start and end tag
+    /// pair, in `canonical` mode, without `self_close`, at the root with `explicit_close_root`
+    /// or declaring a namespace with `explicit_close_ns`
+    fn explicit_close(&self, depth: usize, declares_ns: bool) -> bool {
+        self.canonical
+            || !self.self_close
+            || (self.explicit_close_root && depth == 0)
+            || (self.explicit_close_ns && declares_ns)
+    }
+
+    /// `true` if the open elem without content has to be written as start and end tag pair
+    fn explicit_close_open(&self) -> bool {
+        self.stack.last().is_some_and(|elem| self.explicit_close(self.stack.len() - 1, elem.declares_ns))
+    }
+
+    /// The quote char delimiting attr values
+    fn quote(&self) -> Quote {
+        if self.canonical { Quote::Double } else { self.quote }
+    }
+
+    /// Write the quote char delimiting attr values
+    fn write_quote(&mut self) -> Result {
+        match self.quote() {
+            Quote::Double => self.write_str("\""),
+            Quote::Single => self.write_str("'"),
+        }
+    }
+
+    /// Write the `/>` of a self-closing elem
+    fn write_self_close(&mut self) -> Result {
+        let space = match self.self_close_space {
+            SelfCloseSpace::Never => false,
+            SelfCloseSpace::Always => true,
+            SelfCloseSpace::WithAttrs => self.attrs_written,
+        };
+        self.write_str(if space { " />" } else { "/>" })
+    }
+
+    /// Write the attr `(prefix, local name)` by `f`, in `canonical` mode or with an attr order
+    /// it is buffered with its sort key until the start tag gets completed
+    fn sorted_attr<F>(&mut self, name: (Option<&str>, &str), f: F) -> Result
+    where
+        F: FnOnce(&mut Self) -> Result,
+    {
+        let first = !self.attrs_written;
+        self.attrs_written = true;
+        if !self.canonical && self.attr_order.is_empty() {
+            self.attr_separator(first)?;
+            return f(self);
+        }
+        let attr = self.capture(f)?;
+        let key = self.attr_key(name);
+        self.pending_attrs.push((key, attr));
+        Ok(())
+    }
+
+    /// Write the buffered attrs in sorted order, attrs with equal keys in call order
+    fn write_pending_attrs(&mut self) -> Result {
+        let mut attrs = std::mem::take(&mut self.pending_attrs);
+        attrs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (i, (_, attr)) in attrs.iter().enumerate() {
+            self.attr_separator(i == 0)?;
+            self.write_slice(attr)?;
+        }
+        Ok(())
+    }
+
+    /// Write the separator before an attr, a space or with `attr_wrap` a line break
+    /// and the alignment for any attr but the first
+    fn attr_separator(&mut self, first: bool) -> Result {
+        let width = match self.attr_wrap {
+            Some(style) if self.pretty && self.adaptive.is_none() && !first => match style {
+                AttrWrapStyle::AlignUnderName => self.name_column - 1,
+                AttrWrapStyle::AlignUnderFirstAttr => self.attr_column - 1,
+                AttrWrapStyle::FixedIndent(extra) => self.name_column.saturating_sub(2) + extra,
+            },
+            _ => {
+                self.write_str(" ")?;
+                if first {
+                    self.attr_column = self.column;
+                }
+                return Ok(());
+            }
+        };
+        self.write_str("\n")?;
+        for _ in 0..width {
+            self.write_str(" ")?;
+        }
+        Ok(())
+    }
+
+    /// Split an attr name like `xlink:href` into prefix and local name
+    fn split_name(name: &str) -> (Option<&str>, &str) {
+        match name.split_once(':') {
+            Some((prefix, local)) => (Some(prefix), local),
+            None => (None, name),
+        }
+    }
+
+    /// Sort key of an attr, which may be a namespace declaration
+    fn attr_key(&self, (prefix, local): (Option<&str>, &str)) -> AttrKey {
+        let rank = self
+            .attr_order
+            .iter()
+            .position(|name| Self::split_name(name) == (prefix, local))
+            .unwrap_or(self.attr_order.len());
+        if !self.canonical {
+            return (rank, false, String::new(), String::new());
+        }
+        match prefix {
+            None if local == "xmlns" => (rank, false, String::new(), String::new()),
+            Some("xmlns") => (rank, false, local.to_string(), String::new()),
+            Some(prefix) => (rank, true, self.ns_uri(prefix).to_string(), local.to_string()),
+            None => (rank, true, String::new(), local.to_string()),
+        }
+    }
+
+    /// The namespace uri bound to `prefix` by the open elems, the prefix itself if unbound
+    fn ns_uri<'p>(&self, prefix: &'p str) -> &'p str
+    where
+        'a: 'p,
+    {
+        if prefix == "xml" {
+            return XML_NS;
+        }
+        self.bindings
+            .iter()
+            .rev()
+            .find(|&&(_, bound, _)| bound == Some(prefix))
+            .map_or(prefix, |&(_, _, uri)| uri)
+    }
+
+    /// Write an element or attribute name in the casing set with `set_name_case`,
+    /// the prefix of a qualified name is written as given
+    fn write_name(&mut self, name: &str) -> Result {
+        let name = match name.split_once(':') {
+            Some((prefix, local)) if !matches!(self.name_case, NameCase::AsIs) => {
+                self.write_str(prefix)?;
+                self.write_str(":")?;
+                local
+            }
+            _ => name,
+        };
+        match self.name_case {
+            NameCase::AsIs => self.write_str(name),
+            NameCase::Lower if !name.chars().any(char::is_uppercase) => self.write_str(name),
+            NameCase::Upper if !name.chars().any(char::is_lowercase) => self.write_str(name),
+            NameCase::Lower => {
+                for c in name.chars().flat_map(char::to_lowercase) {
+                    self.write_slice(c.encode_utf8(&mut [0; 4]).as_bytes())?;
+                }
+                Ok(())
+            }
+            NameCase::Upper => {
+                for c in name.chars().flat_map(char::to_uppercase) {
+                    self.write_slice(c.encode_utf8(&mut [0; 4]).as_bytes())?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Check an element or attribute name. Names can not be escaped,
+    /// so whitespace and chars with a meaning in markup are rejected.
+    fn check_name(name: &str) -> Result {
+        let invalid = |c: char| {
+            c.is_whitespace() || matches!(c, '"' | '\'' | '&' | '<' | '>' | '=' | '/')
+        };
+        if name.is_empty() || name.contains(invalid) {
+            return Err(XmlError::InvalidName(name.to_string()).into());
+        }
+        Ok(())
+    }
+
+    /// Check a prefix or local name, which can not contain a `:` either
+    fn check_ncname(name: &str) -> Result {
+        Self::check_name(name)?;
+        if name.contains(':') {
+            return Err(XmlError::InvalidName(name.to_string()).into());
+        }
+        Ok(())
+    }
+
+    /// Escape character data of text content
+    fn escape_text(&mut self, text: &str) -> Result {
+        if !self.preserve_entities {
+            return self.escape(text, false);
+        }
+        let mut rest = text;
+        while let Some(pos) = rest.find('&') {
+            self.escape(&rest[..pos], false)?;
+            let len = entity_ref_len(&rest[pos..]);
+            if len > 0 {
+                self.write_str(&rest[pos..pos + len])?;
+                rest = &rest[pos + len..];
+            } else {
+                self.write_str("&amp;")?;
+                rest = &rest[pos + 1..];
+            }
+        }
+        self.escape(rest, false)
+    }
+
+    /// Escape an attribute value
+    fn escape_attr_value(&mut self, value: &str) -> Result {
+        if !self.normalize_attr_whitespace {
+            return self.escape(value, true);
+        }
+        let words = value
+            .split([' ', '\t', '\n', '\r'])
+            .filter(|word| !word.is_empty());
+        for (i, word) in words.enumerate() {
+            if i > 0 {
+                self.write_str(" ")?;
+            }
+            self.escape(word, true)?;
+        }
+        Ok(())
+    }
+
+    /// Escape all chars with a meaning in markup, in `canonical` mode and with
+    /// minimal escaping only those required in text or in an `attr` value.
+    /// U+0000 is always an error, as not even a character reference can represent it.
+    fn escape(&mut self, text: &str, attr: bool) -> Result {
+        // fast path for the common case of nothing to escape
+        let canonical = self.canonical;
+        let html = self.html_mode && !canonical;
+        let ascii_only = self.ascii_only;
+        let clean = |b: u8| {
+            !matches!(b, b'&' | b'<' | b'>' | b'"' | b'\'' | b'\0' | b'\t' | b'\n' | b'\r')
+                && (b.is_ascii() || !(ascii_only || html))
+        };
+        let substitute = !attr && !self.text_substitutions.is_empty();
+        if self.char_filter.is_none() && !substitute && text.bytes().all(clean) {
+            return self.write_slice(text.as_bytes());
+        }
+        let minimal = canonical || html || self.minimal_escaping;
+        let quote = self.quote();
+        // runs of chars passed through as is are written at once, sliced from `text`
+        let plain_runs = self.char_filter.is_none() && !substitute;
+        let bytes = text.as_bytes();
+        let mut run = 0;
+        for (i, c) in text.char_indices() {
+            if plain_runs && clean(bytes[i]) {
+                continue;
+            }
+            if run < i {
+                self.write_slice(&bytes[run..i])?;
+            }
+            run = i + c.len_utf8();
+            match c {
+                '\0' => return Err(XmlError::NullCharacter.into()),
+                '&' => self.write_str("&amp;")?,
+                '<' => self.write_str("&lt;")?,
+                '"' if (attr && quote == Quote::Double) || !minimal => self.write_str("&quot;")?,
+                // `&apos;` is no entity in HTML 4
+                '\'' if attr && quote == Quote::Single && html => self.write_str("&#39;")?,
+                '\'' if (attr && quote == Quote::Single) || !minimal => self.write_str("&apos;")?,
+                // in text `>` has to be escaped only to not end a `]]>`, even one begun by earlier output
+                '>' if !minimal || (!attr && (canonical || html || self.brackets == 2)) => {
+                    self.write_str("&gt;")?
+                }
+                '\u{A0}' if html => self.write_str("&nbsp;")?,
+                '\r' if canonical => self.char_ref(c)?,
+                '\t' | '\n' if attr && canonical => self.char_ref(c)?,
+                _ if substitute && let Some(i) = self.text_substitutions.iter().position(|&(s, _)| s == c) => {
+                    self.write_substitution(i)?
+                }
+                _ if self.ascii_only && !c.is_ascii() => self.char_ref(c)?,
+                _ if self.char_filter.as_ref().is_some_and(|allowed| !allowed(c)) => self.char_ref(c)?,
+                _ => self.write_slice(&bytes[i..run])?,
+            };
+        }
+        self.write_slice(&bytes[run..])
+    }
+
+    /// Write the replacement of the `i`th text substitution
+    fn write_substitution(&mut self, i: usize) -> Result {
+        // taken out for the time of the write, as it borrows the writer
+        let substitution = std::mem::take(&mut self.text_substitutions[i].1);
+        let result = self.write_str(&substitution);
+        self.text_substitutions[i].1 = substitution;
+        result
+    }
+
+    /// Write a hexadecimal numeric character reference like `&#x1F600;`
+    fn char_ref(&mut self, c: char) -> Result {
+        const HEX: &[u8; 16] = b"0123456789ABCDEF";
+        let mut buf = [0u8; 12];
+        let mut pos = buf.len();
+        buf[pos - 1] = b';';
+        pos -= 1;
+        let mut code = c as u32;
+        loop {
+            pos -= 1;
+            buf[pos] = HEX[(code & 0xF) as usize];
+            code >>= 4;
+            if code == 0 {
+                break;
+            }
+        }
+        pos -= 3;
+        buf[pos..pos + 3].copy_from_slice(b"&#x");
+        self.write_slice(&buf[pos..])
+    }
+
+    /// Begin a text node as child of the current elem. Text following text in the same
+    /// elem continues the node, in `very pretty` mode it is not put into an own line.
+    /// In strict mode only whitespace may be written outside of the root elem.
+    fn begin_text(&mut self, mixed: bool) -> Result {
+        if mixed && self.strict && self.stack.is_empty() {
+            return Err(XmlError::TextAtRoot.into());
+        }
+        if mixed {
+            self.mark_mixed();
+        }
+        let continued = self.last_node == Some(NodeKind::Text) && !self.opened;
+        self.last_node = Some(NodeKind::Text);
+        self.notify(WriterEvent::Text);
+        let nested = self.text_nested();
+        self.begin_child(nested)?;
+        if nested && !continued {
+            self.indent()?;
+        }
+        Ok(())
+    }
+
+    /// With `preserve_mixed` keep the current elem from being indented within,
+    /// as it contains text
+    fn mark_mixed(&mut self) {
+        if self.preserve_mixed && self.mixed_depth.is_none() && !self.stack.is_empty() {
+            self.mixed_depth = Some(self.stack.len());
+        }
+    }
+
+    /// Write a text, escapes the text automatically.
+    /// Consecutive texts are written as one text node.
+    pub fn text(&mut self, text: &str) -> Result {
+        self.begin_text(!text.trim().is_empty())?;
+        match self.wrap_text_at {
+            Some(width) if self.pretty && self.adaptive.is_none() => self.wrap_text(text, width),
+            _ => self.escape_text(text),
+        }
+    }
+
+    /// Write a timestamp as text formatted by its `Display` impl, without allocating,
+    /// see `attr_datetime`
+    pub fn text_datetime(&mut self, dt: &impl fmt::Display) -> Result {
+        self.begin_text(true)?;
+        let mut text = Escaping { xml: &mut *self, attr: false, err: None };
+        let result = fmt::Write::write_fmt(&mut text, format_args!("{}", dt));
+        if let Some(err) = text.err {
+            return Err(err);
+        }
+        result.map_err(|_| io::Error::other("formatting the text failed"))
+    }
+
+    /// Escape a text and wrap it at spaces, so lines do not exceed `width`
+    /// if possible. Continuation lines are indented to the depth of the text.
+    fn wrap_text(&mut self, text: &str, width: usize) -> Result {
+        let depth = self.stack.len();
+        for (i, word) in text.split(' ').enumerate() {
+            if i == 0 {
+                self.escape_text(word)?;
+                continue;
+            }
+            let escaped = self.capture(|xml| xml.escape_text(word))?;
+            let used = self.column - 1;
+            if !word.is_empty() && used > 2 * depth && used + 1 + char_count(&escaped) > width {
+                self.line_break(depth)?;
+            } else {
+                self.write_str(" ")?;
+            }
+            self.write_slice(&escaped)?;
+        }
+        Ok(())
+    }
+
+    /// Write a reference to a declared entity like `&name;`
+    pub fn entity(&mut self, name: &str) -> Result {
+        Self::check_name(name)?;
+        self.begin_text(true)?;
+        self.write_str("&")?;
+        self.write_str(name)?;
+        self.write_str(";")
+    }
+
+    /// Write a text which is escaped already, it is placed just like with `text`
+    /// but written as is, no escaping, use at own risk
+    pub fn text_unescaped(&mut self, text: &str) -> Result {
+        self.check_raw_allowed()?;
+        self.begin_text(!text.trim().is_empty())?;
+        self.write_str(text)
+    }
+
+    /// Write whitespace (spaces, tabs, line breaks) as is, to format the output by hand.
+    /// A pending start tag is completed, so the elem can not be self-closed anymore,
+    /// but unlike `text` nothing is indented or put into an own line.
+    /// The end tag of the elem is placed just like after text.
+    pub fn whitespace(&mut self, ws: &str) -> Result {
+        if !ws.chars().all(|c| matches!(c, ' ' | '\t' | '\n' | '\r')) {
+            return Err(XmlError::NotWhitespace(ws.to_string()).into());
+        }
+        self.last_node = Some(NodeKind::Text);
+        self.notify(WriterEvent::Text);
+        self.begin_child(false)?;
+        self.write_str(ws)
+    }
+
+    /// Write a text streamed from `reader` in chunks, escapes the text automatically.
+    /// The stream has to be valid UTF-8, references kept by `set_preserve_entities`
+    /// must not span chunks.
+    pub fn text_from_reader<R: Read>(&mut self, mut reader: R) -> Result {
+        self.begin_text(true)?;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8");
+        let mut buf = [0u8; 4096];
+        // bytes of a char split by the chunk boundary, carried over to the next chunk
+        let mut carry = 0;
+        loop {
+            let len = match reader.read(&mut buf[carry..]) {
+                Ok(0) => break,
+                Ok(read) => carry + read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            let valid = match str::from_utf8(&buf[..len]) {
+                Ok(text) => text.len(),
+                Err(err) if err.error_len().is_none() => err.valid_up_to(),
+                Err(_) => return Err(invalid()),
+            };
+            let text = str::from_utf8(&buf[..valid]).map_err(|_| invalid())?;
+            self.escape_text(text)?;
+            buf.copy_within(valid..len, 0);
+            carry = len - valid;
+        }
+        if carry > 0 {
+            return Err(invalid());
+        }
+        Ok(())
+    }
+
+    /// Start a line indented for a child of the current elem, like the writer does before
+    /// a node, e.g. to put a `write` fragment into an own line. It respects the mode and
+    /// the depth, in `compact` mode nothing is written. A pending start tag is completed.
+    pub fn write_indent(&mut self) -> Result {
+        self.begin_child(true)?;
+        self.indent()
+    }
+
+    /// Raw write, no escaping, no safety net, use at own risk
+    pub fn write(&mut self, text: &str) -> Result {
+        self.check_raw_allowed()?;
+        self.write_str(text)
+    }
+
+    /// Write markup produced by the writer itself
+    fn write_str(&mut self, text: &str) -> Result {
+        self.write_slice(text.as_bytes())
+    }
+
+    /// Check raw output is allowed, it is not in safe mode
+    fn check_raw_allowed(&self) -> Result {
+        if self.safe {
+            return Err(XmlError::RawWriteForbidden.into());
+        }
+        Ok(())
+    }
+
+    /// Write a block of already formatted xml, no escaping, no safety net, use at own risk.
+    /// In the pretty modes every line of the block is put into an own line at the current
+    /// indentation, keeping the indentation within the block, blank lines are dropped.
+    /// In compact mode the block is written verbatim.
+    pub fn raw_block(&mut self, xml: &str) -> Result {
+        self.check_raw_allowed()?;
+        self.begin_child(true)?;
+        if !self.pretty {
+            return self.write_str(xml);
+        }
+        let lines = xml.lines().filter(|line| !line.trim().is_empty());
+        for line in lines {
+            self.indent()?;
+            self.write_str(line)?;
+        }
+        Ok(())
+    }
+
+    /// Write an elem `name` with the already serialized xml `inner` as its content,
+    /// e.g. a subtree written by another writer. There is no escaping and no safety net,
+    /// in the pretty modes `inner` is re-indented like a `raw_block` and has to be UTF-8.
+    pub fn embed(&mut self, name: &'a str, inner: &[u8]) -> Result {
+        self.check_raw_allowed()?;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "embedded xml is not valid UTF-8");
+        let block = if self.pretty { Some(str::from_utf8(inner).map_err(|_| invalid())?) } else { None };
+        self.begin_elem(name)?;
+        if let Some(block) = block {
+            self.raw_block(block)?;
+        } else {
+            self.begin_child(true)?;
+            self.write_slice(inner)?;
+        }
+        self.end_elem()
+    }
+
+    /// Raw write, no escaping, no safety net, use at own risk
+    fn write_slice(&mut self, slice: &[u8]) -> Result {
+        let content = match self.wrote_anything {
+            true => slice,
+            false => slice.strip_prefix("\u{FEFF}".as_bytes()).unwrap_or(slice),
+        };
+        self.wrote_anything |= !content.is_empty();
+        self.wrote_trailing_newline &= slice.is_empty();
+        self.brackets = match slice.iter().rposition(|&b| b != b']') {
+            Some(last) => (slice.len() - 1 - last).min(2),
+            None => (self.brackets + slice.len()).min(2),
+        };
+        if let Some(buf) = &mut self.capture {
+            buf.extend_from_slice(slice);
+            return Ok(());
+        }
+        self.advance_position(slice);
+        self.track_tokens(slice);
+        if let Some(adaptive) = &mut self.adaptive {
+            adaptive.buf.extend_from_slice(slice);
+            adaptive.pretty_len += slice.len();
+            self.check_threshold()?;
+        } else {
+            self.writer.write_all(slice)?;
+        }
+        Ok(())
+    }
+
+    /// Track whether the output ends within a tag or reference, whoever wrote it.
+    /// A reference ends with its `;` or any byte which can not be part of it.
+    fn track_tokens(&mut self, slice: &[u8]) {
+        if let Some(last) = slice.iter().rposition(|&b| b == b'<' || b == b'>') {
+            self.in_tag = slice[last] == b'<';
+        }
+        let ends_ref = |b: &u8| !(b.is_ascii_alphanumeric() || b"#_-.:".contains(b) || *b >= 0x80);
+        let rest = match slice.iter().rposition(|&b| b == b'&') {
+            Some(amp) => {
+                self.in_ref = true;
+                &slice[amp + 1..]
+            }
+            None => slice,
+        };
+        if self.in_ref && rest.iter().any(ends_ref) {
+            self.in_ref = false;
+        }
+    }
+
+    /// Move the output position over the written bytes
+    fn advance_position(&mut self, slice: &[u8]) {
+        match slice.iter().rposition(|&b| b == b'\n') {
+            Some(last) => {
+                self.line += slice.iter().filter(|&&b| b == b'\n').count();
+                self.column = 1 + char_count(&slice[last + 1..]);
+            }
+            None => self.column += char_count(slice),
+        }
+    }
+
+    /// The output position as `(line, column)` starting at `(1, 1)`, the column counted in chars.
+    /// Output redirected by `capture` is not counted, while `adaptive` mode buffers the output
+    /// the position is that of the pretty output.
+    pub fn position(&self) -> (usize, usize) {
+        (self.line, self.column)
+    }
+
+    /// Run `f` with the output redirected into a separate buffer and return that buffer.
+    /// The structural state (open elems, namespaces, mode) is shared with `f`, so the
+    /// buffer holds exactly the bytes `f` would have written. Re-emit them with `write`
+    /// to keep the document consistent.
+    pub fn capture<F>(&mut self, f: F) -> io::Result<Vec<u8>>
+    where
+        F: FnOnce(&mut Self) -> Result,
+    {
+        let outer = self.capture.replace(Vec::new());
+        let result = f(self);
+        let captured = std::mem::replace(&mut self.capture, outer).unwrap_or_default();
+        result.map(|_| captured)
+    }
+
+    /// Write a CDATA, in `canonical` mode it is written as escaped text
+    pub fn cdata(&mut self, cdata: &str) -> Result {
+        if self.strict && self.stack.is_empty() {
+            return Err(XmlError::TextAtRoot.into());
+        }
+        if self.canonical {
+            return self.text(cdata);
+        }
+        self.last_node = Some(NodeKind::CData);
+        self.notify(WriterEvent::CData);
+        self.mark_mixed();
+        let nested = self.text_nested();
+        self.begin_child(nested)?;
+        if nested {
+            self.indent()?;
+        }
+        self.write_str("<![CDATA[")?;
+        if self.safe {
+            self.write_cdata_content(cdata)?;
+        } else {
+            self.write_str(cdata)?;
+        }
+        self.write_str("]]>")
+    }
+
+    /// Write an XHTML `<script>` elem with `js` wrapped like `//<![CDATA[ ... //]]>`,
+    /// to be valid both as xml and as script. A `]]>` in `js` is split into two
+    /// CDATA sections like `]]]]><![CDATA[>`, which only an xml parser joins again.
+    pub fn script(&mut self, js: &str) -> Result {
+        self.commented_cdata("script", "//", "", js)
+    }
+
+    /// Write an XHTML `<style>` elem with `css` wrapped like `/*<![CDATA[*/ ... /*]]>*/`,
+    /// see `script`
+    pub fn style(&mut self, css: &str) -> Result {
+        self.commented_cdata("style", "/*", "*/", css)
+    }
+
+    /// Write the elem `name` with `content` in a CDATA section, its markers
+    /// in a comment from `begin` to `end` of the content language
+    fn commented_cdata(&mut self, name: &'a str, begin: &str, end: &str, content: &str) -> Result {
+        self.begin_elem(name)?;
+        self.last_node = Some(NodeKind::CData);
+        self.notify(WriterEvent::CData);
+        self.mark_mixed();
+        self.begin_child(false)?;
+        self.write_str(begin)?;
+        self.write_str("<![CDATA[")?;
+        self.write_str(end)?;
+        self.write_str("\n")?;
+        self.write_cdata_content(content)?;
+        self.write_str("\n")?;
+        self.write_str(begin)?;
+        self.write_str("]]>")?;
+        self.write_str(end)?;
+        self.end_elem()
+    }
+
+    /// Write the content of a CDATA section, each `]]>` split into two sections
+    fn write_cdata_content(&mut self, content: &str) -> Result {
+        let mut parts = content.split("]]>");
+        if let Some(first) = parts.next() {
+            self.write_str(first)?;
+        }
+        for part in parts {
+            self.write_str("]]]]><![CDATA[>")?;
+            self.write_str(part)?;
+        }
+        Ok(())
+    }
+
+    /// Write a CDATA streamed from `reader` in chunks, the bytes are written as is.
+    /// Each `]]>` in the stream, even across chunks, is split into two CDATA sections
+    /// like `]]]]><![CDATA[>` to keep the output well-formed.
+    /// In `canonical` mode the stream is written as escaped text, see `text_from_reader`.
+    pub fn cdata_from_reader<R: Read>(&mut self, mut reader: R) -> Result {
+        if self.strict && self.stack.is_empty() {
+            return Err(XmlError::TextAtRoot.into());
+        }
+        if self.canonical {
+            return self.text_from_reader(reader);
+        }
+        self.last_node = Some(NodeKind::CData);
+        self.notify(WriterEvent::CData);
+        self.mark_mixed();
+        let nested = self.text_nested();
+        self.begin_child(nested)?;
+        if nested {
+            self.indent()?;
+        }
+        self.write_str("<![CDATA[")?;
+        let mut buf = [0u8; 4096];
+        // number of `]` written last, up to 2, carried over to the next chunk
+        let mut brackets = 0;
+        loop {
+            let len = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            let mut start = 0;
+            for (i, &b) in buf[..len].iter().enumerate() {
+                if b == b'>' && brackets == 2 {
+                    self.write_slice(&buf[start..i])?;
+                    self.write_str("]]><![CDATA[")?;
+                    start = i;
+                }
+                brackets = if b == b']' { (brackets + 1).min(2) } else { 0 };
+            }
+            self.write_slice(&buf[start..len])?;
+        }
+        self.write_str("]]>")
+    }
+
+    /// Write character data, wrapped in CDATA if `prefer_cdata` and the content
+    /// can be wrapped, escaped like with `text` otherwise
+    pub fn char_data(&mut self, content: &str, prefer_cdata: bool) -> Result {
+        if prefer_cdata && Self::cdata_safe(content) {
+            self.cdata(content)
+        } else {
+            self.text(content)
+        }
+    }
+
+    /// `true` if the content can be wrapped in CDATA, as it does not contain its end `]]>`
+    fn cdata_safe(content: &str) -> bool {
+        !content.contains("]]>")
+    }
+
+    /// Write a comment. Like every child it completes a pending start tag, so attrs
+    /// written afterwards are an `XmlError::AttrOutsideElement`, unless the comment
+    /// is held back by `set_comments_count_as_children(false)`.
+    pub fn comment(&mut self, comment: &str) -> Result {
+        self.last_node = Some(NodeKind::Comment);
+        self.notify(WriterEvent::Comment);
+        if !self.comments_count_as_children
+            && self.very_pretty
+            && self.opened
+            && !self.explicit_close_open()
+        {
+            self.deferred_comments.push(comment.to_string());
+            return Ok(());
+        }
+        self.write_comment(comment)
+    }
+
+    /// Write a comment as child of the current elem
+    fn write_comment(&mut self, comment: &str) -> Result {
+        self.begin_child(true)?;
+        self.indent()?;
+        self.write_str("<!-- ")?;
+        self.escape_text(comment)?;
+        self.write_str(" -->")
+    }
+
+    /// Write a divider comment like `<!-- ===== title ===== -->` with the title centered
+    /// in `width` chars of `=`, for readable sections in generated files.
+    /// An empty title writes only the `=`, a title too long for the width is not padded.
+    /// A `--` in the title is written as `- -`, like with `metadata_comment`.
+    pub fn section(&mut self, title: &str, width: usize) -> Result {
+        self.last_node = Some(NodeKind::Comment);
+        self.notify(WriterEvent::Comment);
+        self.begin_child(true)?;
+        self.indent()?;
+        self.write_str("<!-- ")?;
+        if title.is_empty() {
+            self.fill('=', width)?;
+        } else {
+            let fill = width.saturating_sub(title.chars().count() + 2);
+            self.fill('=', fill / 2)?;
+            if fill > 0 {
+                self.write_str(" ")?;
+            }
+            self.escape_comment_text(title)?;
+            if fill > 0 {
+                self.write_str(" ")?;
+            }
+            self.fill('=', fill - fill / 2)?;
+        }
+        self.write_str(" -->")
+    }
+
+    /// Write a comment from key value pairs like `<!-- generated: 2024-01-01, tool: x -->`,
+    /// for provenance headers of generated files. A `--` within keys or values, which is
+    /// not allowed in comments, is written as `- -`.
+    pub fn metadata_comment(&mut self, pairs: &[(&str, &str)]) -> Result {
+        self.last_node = Some(NodeKind::Comment);
+        self.notify(WriterEvent::Comment);
+        self.begin_child(true)?;
+        self.indent()?;
+        self.write_str("<!-- ")?;
+        for (i, &(key, value)) in pairs.iter().enumerate() {
+            if i > 0 {
+                self.write_str(", ")?;
+            }
+            self.escape_comment_text(key)?;
+            self.write_str(": ")?;
+            self.escape_comment_text(value)?;
+        }
+        self.write_str(" -->")
+    }
+
+    /// Escape a text in a comment, separating the dashes of each `--` by a space
+    fn escape_comment_text(&mut self, text: &str) -> Result {
+        let mut rest = text;
+        while let Some(pos) = rest.find("--") {
+            self.escape_text(&rest[..=pos])?;
+            self.write_str(" ")?;
+            rest = &rest[pos + 1..];
+        }
+        self.escape_text(rest)
+    }
+
+    /// Write `c` `count` times
+    fn fill(&mut self, c: char, count: usize) -> Result {
+        let mut buf = [0; 4];
+        let c = c.encode_utf8(&mut buf);
+        for _ in 0..count {
+            self.write_str(c)?;
+        }
+        Ok(())
+    }
+
+    /// Write a processing instruction like `<?target data?>`,
+    /// with empty `data` it is written as `<?target?>` without a space.
+    /// Like every child it completes a pending start tag, see `comment`.
+    /// The target `xml` is reserved in any case, `data` must not contain `?>`.
+    pub fn pi(&mut self, target: &str, data: &str) -> Result {
+        Self::check_name(target)?;
+        if target.eq_ignore_ascii_case("xml") {
+            return Err(XmlError::InvalidName(target.to_string()).into());
+        }
+        if data.contains("?>") {
+            return Err(XmlError::InvalidPiData(data.to_string()).into());
+        }
+        self.last_node = Some(NodeKind::Pi);
+        self.notify(WriterEvent::Pi(target));
+        self.begin_child(true)?;
+        self.indent()?;
+        self.write_str("<?")?;
+        self.write_str(target)?;
+        if !data.is_empty() {
+            self.write_str(" ")?;
+            self.write_str(data)?;
+        }
+        self.write_str("?>")
+    }
+
+    /// Write an `Event` by dispatching it to the corresponding method
+    pub fn write_event(&mut self, event: Event<'a>) -> Result {
+        match event {
+            Event::Start { name, attrs } => {
+                self.begin_elem(name)?;
+                for &(name, value) in attrs {
+                    self.attr_esc(name, value)?;
+                }
+                Ok(())
+            }
+            Event::End => self.end_elem(),
+            Event::Empty { name, attrs } => self.elem_from_map(name, attrs.iter().copied()),
+            Event::Text(text) => self.text(text),
+            Event::CData(cdata) => self.cdata(cdata),
+            Event::Comment(comment) => self.comment(comment),
+            Event::PI { target, data } => self.pi(target, data),
+            Event::Decl { encoding } => self.dtd(encoding),
+        }
+    }
+
+    /// Write all `events` in order by `write_event`, stopping at the first error
+    pub fn write_events<I>(&mut self, events: I) -> Result
+    where
+        I: IntoIterator<Item = Event<'a>>,
+    {
+        for event in events {
+            self.write_event(event)?;
+        }
+        Ok(())
+    }
+
+    /// Close all open elems. If no root elem has been written, the elem set with
+    /// `set_empty_root` is written, in strict mode it is an error otherwise.
+    pub fn close(&mut self) -> Result {
+        for _ in 0..self.stack.len() {
+            self.end_elem()?;
+        }
+        if !self.wrote_root {
+            if let Some(name) = self.empty_root {
+                self.empty_elem(name)?;
+            } else if self.strict {
+                return Err(XmlError::EmptyDocument.into());
+            }
+        }
+        if self.trailing_newline && self.wrote_root && !self.wrote_trailing_newline {
+            self.write_str("\n")?;
+            self.wrote_trailing_newline = true;
+        }
+        Ok(())
+    }
+
+    /// Close all open elems, write the output buffered in `adaptive` mode
+    /// and flush the underlying Writer
+    pub fn finish(&mut self) -> Result {
+        self.close()?;
+        if let Some(adaptive) = self.adaptive.take() {
+            let mut start = 0;
+            for &(pos, depth) in &adaptive.breaks {
+                self.writer.write_all(&adaptive.buf[start..pos])?;
+                self.writer.write_all(b"\n")?;
+                for _ in 0..depth {
+                    self.writer.write_all(b"  ")?;
+                }
+                start = pos;
+            }
+            self.writer.write_all(&adaptive.buf[start..])?;
+        }
+        self.flush()
+    }
+
+    /// Complete a pending start tag by writing its `>` without ending the elem,
+    /// so a streaming consumer sees it promptly. Children can follow, attrs not.
+    /// The elem is not self-closed anymore in `very pretty` mode.
+    pub fn flush_element(&mut self) -> Result {
+        self.complete_start_tag()
+    }
+
+    /// Flush the underlying Writer, a pending start tag is completed first
+    pub fn flush(&mut self) -> Result {
+        self.complete_start_tag()?;
+        self.writer.flush()
+    }
+
+    /// Flush the underlying Writer only if the output ends at a node boundary, so a
+    /// streaming consumer sees a well-formed prefix of the document. A pending start
+    /// tag is completed first. Nothing is flushed while the output, e.g. by `write`, ends
+    /// within a tag or reference, the next `partial_flush` after it ends flushes it all.
+    pub fn partial_flush(&mut self) -> Result {
+        self.complete_start_tag()?;
+        if self.in_tag || self.in_ref {
+            return Ok(());
+        }
+        self.writer.flush()
+    }
+
+    /// Panic unless all elems are closed and no start tag is pending.
+    /// Meant for tests asserting that a helper left the writer in a clean state.
+    pub fn assert_balanced(&self) {
+        assert!(
+            self.stack.is_empty() && self.ns_stack.is_empty() && !self.opened,
+            "XmlWriter is not balanced, {}",
+            self.debug_state()
+        );
+    }
+
+    /// The kind of node written last, `None` before the first node.
+    /// Raw writes and the xml declaration are not tracked.
+    pub fn last_node(&self) -> Option<NodeKind> {
+        self.last_node
+    }
+
+    /// The namespace prefixes of the open elems, outermost first
+    pub fn namespace_scope(&self) -> &[Option<&'a str>] {
+        &self.ns_stack
+    }
+
+    /// The namespace prefix of the innermost open elem, `None` if it has none
+    /// or no elem is open
+    pub fn current_namespace(&self) -> Option<&'a str> {
+        self.ns_stack.last().copied().flatten()
+    }
+
+    /// Describe the current state, the open elems, their namespaces and the flags
+    pub fn debug_state(&self) -> String {
+        let path: Vec<&str> = self.stack.iter().map(|elem| elem.name).collect();
+        format!(
+            "path: /{}, ns_stack: {:?}, namespace: {:?}, opened: {}, capturing: {}",
+            path.join("/"),
+            self.ns_stack,
+            self.namespace,
+            self.opened,
+            self.capture.is_some()
+        )
+    }
+
+    /// Get a reference to the inner Writer
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Get a mutable reference to the inner Writer,
+    /// writing to it directly bypasses all bookkeeping
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Consume the XmlWriter and return the inner Writer
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<'a, W: Write + AsRef<[u8]>> XmlWriter<'a, W> {
+    /// The bytes written to a sink like `Vec<u8>` so far, to assert intermediate output
+    /// in tests. A pending start tag is not completed, output buffered in `adaptive` mode
+    /// or redirected by `capture` is not included.
+    pub fn peek(&self) -> &[u8] {
+        self.writer.as_ref()
+    }
+}
+
+impl<'a, W: Write + EncodingAware> XmlWriter<'a, W> {
+    /// Write the xml declaration like `dtd`, with the encoding reported by the sink
+    pub fn decl_auto(&mut self) -> Result {
+        self.check_encoding(self.writer.encoding())?;
+        self.write_decl(Self::write_sink_encoding)
+    }
+
+    /// Write the encoding reported by the sink. The name borrows the sink, so it is
+    /// copied in chunks to the stack to be written without allocating.
+    fn write_sink_encoding(&mut self) -> Result {
+        let mut buf = [0; 32];
+        let mut start = 0;
+        loop {
+            let rest = self.writer.encoding().as_bytes().get(start..).unwrap_or_default();
+            if rest.is_empty() {
+                return Ok(());
+            }
+            let len = rest.len().min(buf.len());
+            buf[..len].copy_from_slice(&rest[..len]);
+            self.write_slice(&buf[..len])?;
+            start += len;
+        }
+    }
+}
+
+impl<'a, W: Write + Truncate> XmlWriter<'a, W> {
+    /// Remember the current output position and state, to back out
+    /// of a partially written subtree with `rollback`
+    pub fn checkpoint(&self) -> Checkpoint<'a> {
+        Checkpoint {
+            position: self.writer.position(),
+            captured: self.capture.as_ref().map(Vec::len),
+            buffered: self
+                .adaptive
+                .as_ref()
+                .map(|adaptive| (adaptive.buf.len(), adaptive.breaks.len(), adaptive.pretty_len)),
+            stack: self.stack.clone(),
+            ns_stack: self.ns_stack.clone(),
+            bindings: self.bindings.clone(),
+            ns_restore: self.ns_restore.clone(),
+            pending_attrs: self.pending_attrs.clone(),
+            deferred_comments: self.deferred_comments.clone(),
+            namespace: self.namespace,
+            opened: self.opened,
+            in_subset: self.in_subset,
+            attrs_written: self.attrs_written,
+            name_column: self.name_column,
+            attr_column: self.attr_column,
+            last_node: self.last_node,
+            wrote_anything: self.wrote_anything,
+            wrote_trailing_newline: self.wrote_trailing_newline,
+            wrote_root: self.wrote_root,
+            children: self.children,
+            newline: self.newline,
+            mixed_depth: self.mixed_depth,
+            in_tag: self.in_tag,
+            in_ref: self.in_ref,
+            brackets: self.brackets,
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    /// Truncate the output back to the checkpoint and restore the state it was taken in.
+    /// If `adaptive` mode switched to `compact` in between, it stays `compact`.
+    /// Panics if the checkpoint was taken in or outside of a `capture` which is not active.
+    pub fn rollback(&mut self, cp: Checkpoint<'a>) {
+        match (&mut self.capture, cp.captured) {
+            (Some(buf), Some(len)) => buf.truncate(len),
+            (None, None) => {
+                let mut position = cp.position;
+                match (&mut self.adaptive, cp.buffered) {
+                    (Some(adaptive), Some((len, breaks, pretty_len))) => {
+                        adaptive.buf.truncate(len);
+                        adaptive.breaks.truncate(breaks);
+                        adaptive.pretty_len = pretty_len;
+                    }
+                    // the buffer has been written compact meanwhile
+                    (None, Some((len, _, _))) => position += len,
+                    _ => {}
+                }
+                self.writer.truncate_to(position);
+            }
+            _ => panic!(
+                "Attempted to roll back across a capture, state {}",
+                self.debug_state()
+            ),
+        }
+        self.stack = cp.stack;
+        self.ns_stack = cp.ns_stack;
+        self.bindings = cp.bindings;
+        self.ns_restore = cp.ns_restore;
+        self.pending_attrs = cp.pending_attrs;
+        self.deferred_comments = cp.deferred_comments;
+        self.namespace = cp.namespace;
+        self.opened = cp.opened;
+        self.in_subset = cp.in_subset;
+        self.attrs_written = cp.attrs_written;
+        self.name_column = cp.name_column;
+        self.attr_column = cp.attr_column;
+        self.last_node = cp.last_node;
+        self.wrote_anything = cp.wrote_anything;
+        self.wrote_trailing_newline = cp.wrote_trailing_newline;
+        self.wrote_root = cp.wrote_root;
+        self.children = cp.children;
+        self.newline = cp.newline;
+        self.mixed_depth = cp.mixed_depth;
+        self.in_tag = cp.in_tag;
+        self.in_ref = cp.in_ref;
+        self.brackets = cp.brackets;
+        self.line = cp.line;
+        self.column = cp.column;
+    }
+}
+
+#[allow(unused_must_use)]
+#[cfg(test)]
+mod tests {
+    use super::XmlWriter;
+    use crate::{AttrSet, AttrWrapStyle, EncodingAware, Event, FieldPolicy, NameCase, NamespaceContext, NodeKind, OutputMode, Quote, SelfCloseSpace, XmlError};
+    use std::collections::BTreeMap;
+    use std::io::{self, Read, Write};
+    use std::str;
+
+    /// A sink which only keeps a checksum of the written bytes
+    #[derive(Default)]
+    struct Checksum {
+        sum: u32,
+        len: usize,
+    }
+
+    /// A reader returning a single byte per read
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.split_first() {
+                Some((&first, rest)) if !buf.is_empty() => {
+                    buf[0] = first;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    /// A sink which fails to flush
+    struct Unflushable;
+
+    impl Write for Unflushable {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::Error::other("flush failed"))
+        }
+    }
+
+    impl Write for Checksum {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            for &b in buf {
+                self.sum = self.sum.wrapping_mul(31).wrapping_add(u32::from(b));
+            }
+            self.len += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn compact() {
+        let nsmap = vec![
+            (None, "http://localhost/"),
+            (Some("st"), "http://127.0.0.1/"),
+        ];
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("OTDS");
+        xml.ns_decl(&nsmap);
+        xml.comment("nice to see you");
+        xml.namespace = Some("st");
+        xml.empty_elem("success");
+        xml.begin_elem("node");
+        xml.attr_esc("name", "\"123\"");
+        xml.attr("id", "abc");
+        xml.attr("'unescaped'", "\"123\""); // this WILL generate invalid xml
+        xml.text("'text'");
+        xml.end_elem();
+        xml.namespace = None;
+        xml.begin_elem("stuff");
+        xml.cdata("blablab");
+        // xml.end_elem();
+        // xml.end_elem();
+        xml.close();
+        xml.flush();
+
+        let actual = xml.into_inner();
+        println!("{}", str::from_utf8(&actual).unwrap());
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<OTDS xmlns=\"http://localhost/\" xmlns:st=\"http://127.0.0.1/\"><!-- nice to see you --><st:success/><st:node name=\"&quot;123&quot;\" id=\"abc\" \'unescaped\'=\"\"123\"\">&apos;text&apos;</st:node><stuff><![CDATA[blablab]]></stuff></OTDS>");
+    }
+
+    #[test]
+    fn pretty() {
+        let nsmap = vec![
+            (None, "http://localhost/"),
+            (Some("st"), "http://127.0.0.1/"),
+        ];
+        let mut xml = XmlWriter::pretty_mode(Vec::new());
+        xml.begin_elem("OTDS");
+        xml.ns_decl(&nsmap);
+        xml.comment("nice to see you");
+        xml.namespace = Some("st");
+        xml.empty_elem("success");
+        xml.begin_elem("node");
+        xml.attr_esc("name", "\"123\"");
+        xml.attr("id", "abc");
+        xml.attr("'unescaped'", "\"123\""); // this WILL generate invalid xml
+        xml.text("'text'");
+        xml.end_elem();
+        xml.namespace = None;
+        xml.begin_elem("stuff");
+        xml.cdata("blablab");
+        // xml.end_elem();
+        // xml.end_elem();
+        xml.close();
+        xml.flush();
+
+        let actual = xml.into_inner();
+        println!("{}", str::from_utf8(&actual).unwrap());
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<OTDS xmlns=\"http://localhost/\" xmlns:st=\"http://127.0.0.1/\">\n  <!-- nice to see you -->\n  <st:success/>\n  <st:node name=\"&quot;123&quot;\" id=\"abc\" \'unescaped\'=\"\"123\"\">&apos;text&apos;</st:node>\n  <stuff><![CDATA[blablab]]></stuff></OTDS>");
+    }
+
+    #[test]
+    fn very_pretty() {
+        let nsmap = vec![
+            (None, "http://localhost/"),
+            (Some("st"), "http://127.0.0.1/"),
+        ];
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.begin_elem("OTDS");
+        xml.ns_decl(&nsmap);
+        xml.comment("nice to see you");
+        xml.namespace = Some("st");
+        xml.empty_elem("success");
+        xml.begin_elem("node");
+        xml.attr_esc("name", "\"123\"");
+        xml.attr("id", "abc");
+        xml.attr("'unescaped'", "\"123\""); // this WILL generate invalid xml
+        xml.text("'text'");
+        xml.end_elem();
+        xml.namespace = None;
+        xml.begin_elem("stuff");
+        xml.cdata("blablab");
+        // xml.end_elem();
+        // xml.end_elem();
+        xml.close();
+        xml.flush();
+
+        let actual = xml.into_inner();
+        println!("{}", str::from_utf8(&actual).unwrap());
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<OTDS xmlns=\"http://localhost/\" xmlns:st=\"http://127.0.0.1/\">\n  <!-- nice to see you -->\n  <st:success/>\n  <st:node name=\"&quot;123&quot;\" id=\"abc\" \'unescaped\'=\"\"123\"\">\n    &apos;text&apos;\n  </st:node>\n  <stuff>\n    <![CDATA[blablab]]>\n  </stuff>\n</OTDS>");
+    }
+
+    #[test]
+    fn comment() {
+        let mut xml = XmlWriter::pretty_mode(Vec::new());
+        xml.comment("comment");
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<!-- comment -->");
+    }
+
+    #[test]
+    fn checksum_sink() {
+        let mut xml = XmlWriter::compact_mode(Checksum::default());
+        xml.begin_elem("root");
+        xml.text("text");
+        assert_eq!(xml.get_ref().len, "<root>text".len());
+        xml.close();
+
+        let mut expected = Checksum::default();
+        expected.write_all(b"<root>text</root>");
+        let actual = xml.into_inner();
+        assert_eq!(actual.len, expected.len);
+        assert_eq!(actual.sum, expected.sum);
+    }
+
+    #[test]
+    fn capture() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        let captured = xml.capture(|xml| {
+            xml.begin_elem("node");
+            xml.text("text");
+            xml.end_elem()
+        });
+        let captured = captured.unwrap();
+        assert_eq!(str::from_utf8(&captured).unwrap(), "><node>text</node>");
+        assert_eq!(xml.get_ref().as_slice(), b"<root");
+        xml.write(str::from_utf8(&captured).unwrap());
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root><node>text</node></root>");
+    }
+
+    #[test]
+    fn ascii_only() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_ascii_only(true);
+        xml.begin_elem("root");
+        xml.attr_esc("name", "café");
+        xml.text("smile 😀 & go");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root name=\"caf&#xE9;\">smile &#x1F600; &amp; go</root>");
+    }
+
+    #[test]
+    fn utf8_passthrough() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.elem_text("root", "café 😀");
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root>café 😀</root>");
+    }
+
+    #[test]
+    fn end_elem_name() {
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.begin_elem("node");
+        assert_eq!(xml.end_elem_name().unwrap(), "node");
+        assert_eq!(xml.end_elem_name().unwrap(), "root");
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root>\n  <node/>\n</root>");
+    }
+
+    #[test]
+    fn readable() {
+        let mut xml = XmlWriter::readable_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.comment("nice to see you");
+        xml.begin_elem("name");
+        xml.text("value");
+        xml.end_elem();
+        xml.begin_elem("empty");
+        xml.end_elem();
+        xml.begin_elem("mixed");
+        xml.text("text");
+        xml.begin_elem("child");
+        xml.cdata("blablab");
+        xml.end_elem();
+        xml.text("tail");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root>\n  <!-- nice to see you -->\n  <name>value</name>\n  <empty/>\n  <mixed>text\n    <child><![CDATA[blablab]]></child>\n    tail\n  </mixed>\n</root>");
+    }
+
+    #[test]
+    fn declaration_first() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_strict(true);
+        xml.dtd("utf-8").unwrap();
+        let err = xml.dtd("utf-8").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::DeclarationNotFirst));
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_strict(true);
+        xml.comment("comment");
+        let err = xml.dtd("utf-8").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::DeclarationNotFirst));
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<!-- comment -->");
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_strict(true);
+        xml.bom().unwrap();
+        xml.dtd("utf-8").unwrap();
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "\u{FEFF}<?xml version=\"1.0\" encoding=\"utf-8\" ?>\n");
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_strict(true);
+        xml.write("\u{FEFF}").unwrap();
+        xml.dtd("utf-8").unwrap();
+        let err = xml.dtd("utf-8").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::DeclarationNotFirst));
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_strict(true);
+        xml.write("\u{FEFF} ").unwrap();
+        let err = xml.dtd("utf-8").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::DeclarationNotFirst));
+    }
+
+    #[test]
+    fn declaration_not_first_lenient() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.comment("comment");
+        assert!(xml.dtd("utf-8").is_ok());
+    }
+
+    #[test]
+    fn escape_contexts() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.attr_esc("path", "C:\\dir <\"a\" & 'b'>");
+        let err = xml.attr_esc("na\"me", "value").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::InvalidName("na\"me".to_string())));
+        assert!(xml.attr_esc("", "value").is_err());
+        xml.text("C:\\dir <\"a\" & 'b'>");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root path=\"C:\\dir &lt;&quot;a&quot; &amp; &apos;b&apos;&gt;\">C:\\dir &lt;&quot;a&quot; &amp; &apos;b&apos;&gt;</root>");
+    }
+
+    #[test]
+    fn default_namespace() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.namespace = Some("st");
+        xml.begin_elem("a");
+        xml.begin_elem_default_ns("b", "http://localhost/");
+        xml.empty_elem("c");
+        xml.end_elem();
+        xml.empty_elem("d");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root><st:a><b xmlns=\"http://localhost/\"><c/></b><st:d/></st:a></root>");
+    }
+
+    #[test]
+    fn events() {
+        let mut xml = XmlWriter::pretty_mode(Vec::new());
+        xml.write_event(Event::Decl { encoding: "utf-8" });
+        xml.write_event(Event::PI { target: "xml-stylesheet", data: "href=\"style.xsl\"" });
+        xml.write_event(Event::Start { name: "root", attrs: &[("id", "<1>")] });
+        xml.write_event(Event::Comment("nice to see you"));
+        xml.write_event(Event::Empty { name: "node", attrs: &[("a", "1"), ("b", "2")] });
+        xml.write_event(Event::Text("'text'"));
+        xml.write_event(Event::CData("blablab"));
+        xml.write_event(Event::End);
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<?xml version=\"1.0\" encoding=\"utf-8\" ?>\n<?xml-stylesheet href=\"style.xsl\"?>\n<root id=\"&lt;1&gt;\">\n  <!-- nice to see you -->\n  <node a=\"1\" b=\"2\"/>&apos;text&apos;<![CDATA[blablab]]></root>");
+    }
+
+    #[test]
+    fn flush_completes_start_tag() {
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.attr("id", "1");
+        xml.flush();
+        assert_eq!(xml.get_ref().as_slice(), b"<root id=\"1\">");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root id=\"1\">\n</root>");
+    }
+
+    #[test]
+    fn flush_error() {
+        let mut xml = XmlWriter::compact_mode(Unflushable);
+        xml.begin_elem("root");
+        let err = xml.flush().unwrap_err();
+        assert_eq!(err.to_string(), "flush failed");
+    }
+
+    #[test]
+    fn normalize_attr_whitespace() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_normalize_attr_whitespace(true);
+        xml.begin_elem("root");
+        xml.attr_esc("name", " \t a  b\r\n&  c ");
+        xml.attr("raw", " a  b ");
+        xml.text(" a  b ");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root name=\"a b &amp; c\" raw=\" a  b \"> a  b </root>");
+    }
+
+    #[test]
+    fn empty_document() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.close().unwrap();
+        assert!(xml.get_ref().is_empty());
+
+        xml.set_strict(true);
+        let err = xml.close().unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::EmptyDocument));
+
+        xml.dtd("utf-8");
+        xml.set_empty_root(Some("root"));
+        xml.close().unwrap();
+        xml.close().unwrap();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<?xml version=\"1.0\" encoding=\"utf-8\" ?>\n<root/>");
+    }
+
+    #[test]
+    fn raw_block() {
+        let block = "<sig>\n  <value>abc</value>\n\n</sig>\n";
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.begin_elem("node");
+        xml.raw_block(block);
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root>\n  <node>\n    <sig>\n      <value>abc</value>\n    </sig>\n  </node>\n</root>");
+
+        let mut xml = XmlWriter::pretty_mode(Vec::new());
+        xml.raw_block(block);
+        xml.begin_elem("root");
+        xml.raw_block(block);
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<sig>\n  <value>abc</value>\n</sig>\n<root>\n  <sig>\n    <value>abc</value>\n  </sig></root>");
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.raw_block(block);
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root><sig>\n  <value>abc</value>\n\n</sig>\n</root>");
+    }
+
+    #[test]
+    fn preserve_entities() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_preserve_entities(true);
+        xml.begin_elem("root");
+        xml.attr_esc("name", "&amp;");
+        xml.text("&amp; &#169; &#xA9; &copy; & &; &#; &#xZ; &a b; <&lt;>");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root name=\"&amp;amp;\">&amp; &#169; &#xA9; &copy; &amp; &amp;; &amp;#; &amp;#xZ; &amp;a b; &lt;&lt;&gt;</root>");
+    }
+
+    #[test]
+    fn debug_state() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.assert_balanced();
+        xml.begin_elem("root");
+        xml.namespace = Some("st");
+        xml.begin_elem("node");
+        assert_eq!(xml.debug_state(), "path: /root/node, ns_stack: [None, Some(\"st\")], namespace: Some(\"st\"), opened: true, capturing: false");
+        xml.close();
+        xml.assert_balanced();
+    }
+
+    #[test]
+    #[should_panic(expected = "XmlWriter is not balanced, path: /root")]
+    fn assert_balanced() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.assert_balanced();
+    }
+
+    #[test]
+    fn adaptive() {
+        fn document(xml: &mut XmlWriter<Vec<u8>>) {
+            xml.begin_elem("root");
+            xml.begin_elem("node");
+            xml.attr("id", "1");
+            xml.text("text");
+            xml.end_elem();
+            xml.empty_elem("empty");
+            xml.finish();
+        }
+
+        let pretty = "<root>\n  <node id=\"1\">text</node>\n  <empty/></root>";
+        let mut xml = XmlWriter::adaptive_mode(Vec::new(), pretty.len());
+        document(&mut xml);
+        assert_eq!(str::from_utf8(xml.get_ref()).unwrap(), pretty);
+
+        let mut xml = XmlWriter::adaptive_mode(Vec::new(), pretty.len() - 1);
+        document(&mut xml);
+        assert_eq!(str::from_utf8(xml.get_ref()).unwrap(), "<root><node id=\"1\">text</node><empty/></root>");
+
+        let mut xml = XmlWriter::adaptive_mode(Vec::new(), 10);
+        xml.begin_elem("root");
+        xml.text("some text");
+        assert_eq!(xml.get_ref().as_slice(), b"<root>some text");
+    }
+
+    #[test]
+    fn text_from_reader() {
+        let text = "café 😀 & <tag>".repeat(1000);
+        let escaped = "café 😀 &amp; &lt;tag&gt;".repeat(1000);
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.text_from_reader(text.as_bytes()).unwrap();
+        xml.close();
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), format!("<root>{}</root>", escaped));
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.text_from_reader(Trickle("é😀&".as_bytes())).unwrap();
+        xml.close();
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root>é😀&amp;</root>");
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        let err = xml.text_from_reader(&b"ab\xFFcd"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = xml.text_from_reader(&"é".as_bytes()[..1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn very_pretty_no_trailing_newline() {
+        fn closed(build: fn(&mut XmlWriter<Vec<u8>>)) -> String {
+            let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+            xml.dtd("utf-8");
+            build(&mut xml);
+            xml.close();
+            String::from_utf8(xml.into_inner()).unwrap()
+        }
+
+        let documents = [
+            closed(|xml| { xml.begin_elem("root"); }),
+            closed(|xml| { xml.begin_elem("root"); xml.text("text"); }),
+            closed(|xml| { xml.begin_elem("root"); xml.begin_elem("node"); xml.cdata("cdata"); }),
+            closed(|xml| { xml.begin_elem("root"); xml.empty_elem("node"); xml.end_elem(); }),
+            closed(|xml| { xml.begin_elem("root"); xml.begin_elem("node"); xml.end_elem(); xml.comment("comment"); }),
+        ];
+        for document in &documents {
+            assert!(document.ends_with('>'), "{:?}", document);
+        }
+        assert_eq!(documents[2], "<?xml version=\"1.0\" encoding=\"utf-8\" ?>\n<root>\n  <node>\n    <![CDATA[cdata]]>\n  </node>\n</root>");
+    }
+
+    #[test]
+    fn attr_qname() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_strict(true);
+        xml.begin_elem("root");
+        xml.ns_decl(&vec![(Some("xlink"), "http://www.w3.org/1999/xlink")]);
+        xml.begin_elem("a");
+        xml.attr_qname("xlink", "href", "a&b").unwrap();
+        xml.attr_qname("xml", "lang", "en").unwrap();
+        let err = xml.attr_qname("xsi", "type", "t").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::UndeclaredPrefix("xsi".to_string())));
+        xml.end_elem();
+        xml.end_elem();
+        xml.begin_elem("other");
+        assert!(xml.attr_qname("xlink", "href", "a").is_err());
+        xml.set_strict(false);
+        xml.attr_qname("xlink", "href", "a").unwrap();
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root xmlns:xlink=\"http://www.w3.org/1999/xlink\"><a xlink:href=\"a&amp;b\" xml:lang=\"en\"></a></root><other xlink:href=\"a\"></other>");
+    }
+
+    #[test]
+    fn elem_from_map() {
+        let mut record = BTreeMap::new();
+        record.insert("name", String::from("<name>"));
+        record.insert("id", String::from("1"));
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("records");
+        xml.elem_from_map("record", &record);
+        xml.elem_from_map("record", vec![("b", "2"), ("a", "1")]);
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<records><record id=\"1\" name=\"&lt;name&gt;\"/><record b=\"2\" a=\"1\"/></records>");
+    }
+
+    #[test]
+    fn pretty_text_inline() {
+        let mut xml = XmlWriter::pretty_mode(Vec::new());
+        xml.begin_elem("a");
+        xml.text("hi");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<a>hi</a>");
+
+        let mut xml = XmlWriter::pretty_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.begin_elem("a");
+        xml.text("hi");
+        xml.text(" there");
+        xml.end_elem();
+        xml.begin_elem("b");
+        xml.cdata("data");
+        xml.end_elem();
+        xml.elem_text("c", "text");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root>\n  <a>hi there</a>\n  <b><![CDATA[data]]></b>\n  <c>text</c></root>");
+    }
+
+    #[test]
+    fn root_level_comments() {
+        fn document(mut xml: XmlWriter<Vec<u8>>) -> String {
+            xml.comment("leading");
+            xml.begin_elem("root");
+            xml.empty_elem("node");
+            xml.close();
+            xml.comment("trailing");
+            xml.comment("another");
+            String::from_utf8(xml.into_inner()).unwrap()
+        }
+
+        assert_eq!(document(XmlWriter::compact_mode(Vec::new())), "<!-- leading --><root><node/></root><!-- trailing --><!-- another -->");
+        assert_eq!(document(XmlWriter::pretty_mode(Vec::new())), "<!-- leading -->\n<root>\n  <node/></root>\n<!-- trailing -->\n<!-- another -->");
+        assert_eq!(document(XmlWriter::very_pretty_mode(Vec::new())), "<!-- leading -->\n<root>\n  <node/>\n</root>\n<!-- trailing -->\n<!-- another -->");
+    }
+
+    #[test]
+    fn apply_attrs() {
+        let cell = AttrSet::new().add("class", "c").add("span", "2");
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("row");
+        xml.apply_attrs(&AttrSet::new());
+        for _ in 0..2 {
+            xml.begin_elem("cell");
+            xml.apply_attrs(&cell);
+            xml.attr("id", "1");
+            xml.end_elem();
+        }
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<row><cell class=\"c\" span=\"2\" id=\"1\"></cell><cell class=\"c\" span=\"2\" id=\"1\"></cell></row>");
+    }
+
+    #[test]
+    fn name_case() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_name_case(NameCase::Lower);
+        xml.begin_elem("DIV");
+        xml.attr("Class", "A");
+        xml.attr_esc("ID", "B");
+        xml.elem_text("Span", "Text");
+        xml.empty_elem("BR");
+        xml.end_elem();
+        xml.set_name_case(NameCase::Upper);
+        xml.elem("p");
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<div class=\"A\" id=\"B\"><span>Text</span><br/></div><P/>");
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_name_case(NameCase::Lower);
+        xml.begin_elem("SVG:Rect");
+        xml.attr_esc("XLink:HRef", "#a");
+        xml.end_elem();
+        xml.begin_qname(Some("SVG"), "Circle");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<SVG:rect XLink:href=\"#a\"></SVG:rect><SVG:circle></SVG:circle>");
+    }
+
+    #[test]
+    fn whitespace() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.whitespace("\n  ");
+        xml.elem("a");
+        xml.whitespace("\n");
+        xml.end_elem();
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root>\n  <a/>\n</root>");
+
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.begin_elem("a");
+        xml.whitespace(" ");
+        xml.end_elem();
+        xml.begin_elem("b");
+        xml.end_elem();
+        let err = xml.whitespace(" x").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::NotWhitespace(" x".to_string())));
+        xml.close();
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root>\n  <a> \n  </a>\n  <b/>\n</root>");
+    }
+
+    #[test]
+    fn attr_outside_element() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        let err = xml.ns_decl(&vec![(Some("a"), "urn:a")]).unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::AttrOutsideElement));
+        xml.begin_elem("root");
+        xml.text("t");
+        let err = xml.attr("a", "1").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::AttrOutsideElement));
+        assert!(xml.attr_esc("a", "1").is_err());
+        assert!(xml.attr_qname("xml", "lang", "en").is_err());
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root>t</root>");
+    }
+
+    #[test]
+    fn pi() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.pi("marker", "");
+        xml.begin_elem("root");
+        xml.pi("php", "echo 1;");
+        xml.end_elem();
+        let err = xml.pi("bad target", "").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::InvalidName("bad target".to_string())));
+        let err = xml.pi("XmL", "").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::InvalidName("XmL".to_string())));
+        let err = xml.pi("t", "a?>b").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::InvalidPiData("a?>b".to_string())));
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<?marker?><root><?php echo 1;?></root>");
+    }
+
+    #[test]
+    fn rollback() {
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.attr("a", "1");
+        let cp = xml.checkpoint();
+        xml.begin_elem("draft");
+        xml.ns_decl(&vec![(Some("d"), "urn:d")]);
+        xml.text("invalid");
+        xml.rollback(cp);
+        xml.attr("b", "2");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root a=\"1\" b=\"2\"/>");
+
+        let mut xml = XmlWriter::adaptive_mode(Vec::new(), 30);
+        xml.begin_elem("root");
+        let cp = xml.checkpoint();
+        xml.elem_text("long", "text exceeding the threshold");
+        xml.rollback(cp);
+        xml.elem("a");
+        xml.finish();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root><a/></root>");
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        let cp = xml.checkpoint();
+        xml.begin_doctype("r");
+        xml.rollback(cp);
+        let err = xml.entity_decl("e", "v").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::OutsideDoctypeSubset));
+        xml.elem("r");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<r/>");
+    }
+
+    #[test]
+    fn text_unescaped() {
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.text_unescaped("<b>bold</b> &amp;");
+        xml.begin_elem("empty");
+        xml.end_elem();
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root>\n  <b>bold</b> &amp;\n  <empty/>\n</root>");
+    }
+
+    #[test]
+    fn canonical() {
+        let mut xml = XmlWriter::canonical_mode(Vec::new());
+        xml.dtd("utf-8");
+        xml.begin_elem("doc");
+        xml.attr("b", "2");
+        xml.ns_decl(&vec![(Some("z"), "urn:z"), (Some("y"), "urn:zz"), (None, "urn:default")]);
+        xml.attr_qname("z", "a", "<>\"'&\t\n\r");
+        xml.attr_qname("y", "a", "1");
+        xml.attr_esc("a", "1");
+        xml.elem("e");
+        xml.elem_from_map("f", [("y", "2"), ("x", "1")]);
+        xml.begin_elem("g");
+        xml.end_elem();
+        xml.text("a>b\"c'\r");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<doc xmlns=\"urn:default\" xmlns:y=\"urn:zz\" xmlns:z=\"urn:z\" a=\"1\" b=\"2\" \
+            z:a=\"&lt;>&quot;'&amp;&#x9;&#xA;&#xD;\" y:a=\"1\">\
+            <e></e><f x=\"1\" y=\"2\"></f><g></g>a&gt;b\"c'&#xD;</doc>"
+        );
+    }
+
+    #[test]
+    fn self_close_space() {
+        fn document(space: SelfCloseSpace) -> String {
+            let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+            xml.set_self_close_space(space);
+            xml.begin_elem("p");
+            xml.empty_elem("br");
+            xml.elem_from_map("input", [("type", "x")]);
+            xml.begin_elem("img");
+            xml.attr("src", "a");
+            xml.end_elem();
+            xml.begin_elem("hr");
+            xml.end_elem();
+            xml.close();
+            String::from_utf8(xml.into_inner()).unwrap()
+        }
+
+        assert_eq!(document(SelfCloseSpace::Never), "<p>\n  <br/>\n  <input type=\"x\"/>\n  <img src=\"a\"/>\n  <hr/>\n</p>");
+        assert_eq!(document(SelfCloseSpace::Always), "<p>\n  <br />\n  <input type=\"x\" />\n  <img src=\"a\" />\n  <hr />\n</p>");
+        assert_eq!(document(SelfCloseSpace::WithAttrs), "<p>\n  <br/>\n  <input type=\"x\" />\n  <img src=\"a\" />\n  <hr/>\n</p>");
+    }
+
+    #[test]
+    fn last_node() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        assert_eq!(xml.last_node(), None);
+        xml.pi("target", "");
+        assert_eq!(xml.last_node(), Some(NodeKind::Pi));
+        xml.begin_elem("root");
+        assert_eq!(xml.last_node(), Some(NodeKind::StartTag));
+        xml.attr("a", "1");
+        assert_eq!(xml.last_node(), Some(NodeKind::StartTag));
+        xml.text("t");
+        assert_eq!(xml.last_node(), Some(NodeKind::Text));
+        xml.empty_elem("e");
+        assert_eq!(xml.last_node(), Some(NodeKind::Empty));
+        xml.comment("c");
+        assert_eq!(xml.last_node(), Some(NodeKind::Comment));
+        xml.cdata("d");
+        assert_eq!(xml.last_node(), Some(NodeKind::CData));
+        xml.elem_text("e", "t");
+        assert_eq!(xml.last_node(), Some(NodeKind::EndTag));
+        xml.whitespace(" x");
+        assert_eq!(xml.last_node(), Some(NodeKind::EndTag));
+        xml.end_elem();
+        assert_eq!(xml.last_node(), Some(NodeKind::EndTag));
+    }
+
+    #[test]
+    fn minimal_escaping() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_minimal_escaping(true);
+        xml.begin_elem("root");
+        xml.attr_esc("a", "<a href=\"x\">'&'</a>");
+        xml.text("<a href=\"x\">'&'</a> ]]> ]>");
+        xml.end_elem();
+        xml.set_minimal_escaping(false);
+        xml.elem_from_map("b", [("a", "'>\"")]);
+        xml.set_minimal_escaping(true);
+        xml.begin_elem("c");
+        xml.text("x]]");
+        xml.text(">y]");
+        xml.text_from_reader("]>".as_bytes());
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<root a=\"&lt;a href=&quot;x&quot;>'&amp;'&lt;/a>\">&lt;a href=\"x\">'&amp;'&lt;/a> ]]&gt; ]></root>\
+            <b a=\"&apos;&gt;&quot;\"/><c>x]]&gt;y]]&gt;</c>"
+        );
+    }
+
+    #[test]
+    fn entity_decl() {
+        let mut xml = XmlWriter::pretty_mode(Vec::new());
+        let err = xml.entity_decl("e", "v").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::OutsideDoctypeSubset));
+        xml.begin_doctype("doc");
+        xml.entity_decl("company", "Smith & \"Sons\"");
+        xml.entity_decl("rate", "100%");
+        xml.end_doctype();
+        assert!(xml.end_doctype().is_err());
+        xml.begin_elem("doc");
+        xml.entity("company");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<!DOCTYPE doc [\n  <!ENTITY company \"Smith &amp; &quot;Sons&quot;\">\n  \
+            <!ENTITY rate \"100&#x25;\">\n]>\n<doc>&company;</doc>"
+        );
+    }
+
+    #[test]
+    fn char_data() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.char_data("a < b", true);
+        xml.char_data("a < b", false);
+        xml.char_data("x]]>y", true);
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root><![CDATA[a < b]]>a &lt; bx]]&gt;y</root>");
+    }
+
+    #[test]
+    fn max_depth() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_max_depth(Some(2));
+        xml.begin_elem("a");
+        xml.begin_elem("b");
+        let err = xml.begin_elem("c").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::MaxDepthExceeded(2)));
+        assert!(xml.empty_elem("c").is_err());
+        assert!(xml.elem_text("c", "t").is_err());
+        xml.end_elem();
+        xml.empty_elem("c");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<a><b></b><c/></a>");
+    }
+
+    #[test]
+    fn elem_text_attrs() {
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.begin_elem("p");
+        xml.elem_text_attrs("a", &[("href", "x&y"), ("title", "t")], "link");
+        xml.namespace = Some("ns");
+        xml.elem_text("b", "bold");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<p>\n  <a href=\"x&amp;y\" title=\"t\">link</a>\n  <ns:b>bold</ns:b>\n</p>"
+        );
+    }
+
+    #[test]
+    fn null_character() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        let err = xml.attr_esc("a", "x\0").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::NullCharacter));
+        let err = xml.text("\0").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::NullCharacter));
+        xml.set_ascii_only(true);
+        assert!(xml.text("\0").is_err());
+    }
+
+    #[test]
+    fn attr_order() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_attr_order(&["id", "xmlns:x", "x:type"]);
+        xml.begin_elem("root");
+        xml.attr("b", "1");
+        xml.attr_qname("x", "type", "t");
+        xml.attr("a", "2");
+        xml.ns_decl(&vec![(Some("x"), "urn:x")]);
+        xml.attr_esc("id", "3");
+        xml.elem_from_map("e", [("z", "1"), ("id", "2")]);
+        xml.elem_text_attrs("f", &[("y", "1"), ("id", "2")], "t");
+        xml.set_attr_order(&[]);
+        xml.elem_from_map("g", [("z", "1"), ("id", "2")]);
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<root id=\"3\" xmlns:x=\"urn:x\" x:type=\"t\" b=\"1\" a=\"2\">\
+            <e id=\"2\" z=\"1\"/><f id=\"2\" y=\"1\">t</f><g z=\"1\" id=\"2\"/></root>"
+        );
+    }
+
+    #[test]
+    fn flush_element() {
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.begin_elem("stream");
+        xml.attr("id", "1");
+        xml.flush_element();
+        assert_eq!(xml.get_ref().as_slice(), b"<stream id=\"1\">");
+        assert!(xml.attr("late", "1").is_err());
+        xml.flush_element();
+        xml.empty_elem("item");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<stream id=\"1\">\n  <item/>\n</stream>");
+    }
+
+    #[test]
+    fn explicit_close_root() {
+        fn document(build: fn(&mut XmlWriter<Vec<u8>>)) -> String {
+            let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+            xml.set_explicit_close_root(true);
+            build(&mut xml);
+            xml.close();
+            String::from_utf8(xml.into_inner()).unwrap()
+        }
+
+        assert_eq!(document(|xml| { xml.begin_elem("root"); }), "<root></root>");
+        assert_eq!(document(|xml| { xml.empty_elem("root"); }), "<root></root>");
+        assert_eq!(
+            document(|xml| {
+                xml.begin_elem("root");
+                xml.begin_elem("a");
+                xml.end_elem();
+                xml.empty_elem("b");
+            }),
+            "<root>\n  <a/>\n  <b/>\n</root>"
+        );
+    }
+
+    #[test]
+    fn position() {
+        let mut xml = XmlWriter::pretty_mode(Vec::new());
+        assert_eq!(xml.position(), (1, 1));
+        xml.begin_elem("root");
+        xml.begin_elem("a");
+        assert_eq!(xml.position(), (2, 5));
+        xml.capture(|xml| xml.text("not counted"));
+        xml.text("é\nx");
+        assert_eq!(xml.position(), (3, 2));
+        xml.close();
+        assert_eq!(xml.position(), (3, 13));
+
+        let mut xml = XmlWriter::adaptive_mode(Vec::new(), 100);
+        xml.begin_elem("root");
+        xml.empty_elem("a");
+        assert_eq!(xml.position(), (2, 7));
+    }
+
+    #[test]
+    fn section() {
+        let mut xml = XmlWriter::pretty_mode(Vec::new());
+        xml.begin_elem("config");
+        xml.section("Network", 20);
+        xml.section("", 5);
+        xml.section("Too long for the width", 5);
+        xml.section("a--b", 20);
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<config>\n  <!-- ===== Network ====== -->\n  <!-- ===== -->\n  <!-- Too long for the width -->\n  <!-- ======= a- -b ======= --></config>"
+        );
+    }
+
+    #[test]
+    fn wrap_text() {
+        let text = "Lorem ipsum dolor & sit amet,  consectetur adipiscing elit";
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.set_wrap_text_at(Some(20));
+        xml.begin_elem("doc");
+        xml.begin_elem("p");
+        xml.text(text);
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<doc>\n  <p>\n    Lorem ipsum\n    dolor &amp; sit\n    amet, \n    consectetur\n    adipiscing elit\n  </p>\n</doc>"
+        );
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_wrap_text_at(Some(20));
+        xml.text(text);
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "Lorem ipsum dolor &amp; sit amet,  consectetur adipiscing elit");
+
+        // the spaces are kept when adaptive mode writes compact
+        let mut xml = XmlWriter::adaptive_mode(Vec::new(), 40);
+        xml.set_wrap_text_at(Some(12));
+        xml.begin_elem("p");
+        xml.text("aaaa bbbb cccc dddd");
+        xml.text(" and some more text exceeding the threshold");
+        xml.finish();
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<p>aaaa bbbb cccc dddd and some more text exceeding the threshold</p>"
+        );
+    }
+
+    #[test]
+    fn map_entries() {
+        let mut map = BTreeMap::new();
+        map.insert("b", "2 < 3".to_string());
+        map.insert("a&", "1".to_string());
+        let mut xml = XmlWriter::pretty_mode(Vec::new());
+        xml.begin_elem("config");
+        xml.map_entries("entry", "key", &map);
+        xml.map_entries("entry", "key", vec![("c", "3")]);
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<config>\n  <entry key=\"a&amp;\">1</entry>\n  <entry key=\"b\">2 &lt; 3</entry>\n  <entry key=\"c\">3</entry></config>"
+        );
+    }
+
+    #[test]
+    fn safe_mode() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_safe_mode(true);
+        xml.begin_elem("root");
+        let err = xml.write("<evil/>").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::RawWriteForbidden));
+        assert!(xml.raw_block("<evil/>").is_err());
+        assert!(xml.text_unescaped("<evil/>").is_err());
+        let err = xml.attr("b", "\"><evil/>").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::RawWriteForbidden));
+        assert!(xml.attr_raw("b", "\"><evil/>").is_err());
+        xml.attr_esc("b", "\"><safe/>");
+        xml.ns_decl(&vec![(Some("a"), "\"/><evil")]);
+        let err = xml.dtd("utf-8\" ?><evil/").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::InvalidEncoding("utf-8\" ?><evil/".to_string())));
+        xml.text("<safe/>");
+        xml.cdata("a]]><evil/>");
+        xml.end_elem();
+        xml.set_safe_mode(false);
+        xml.write("<!-- raw -->");
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<root b=\"&quot;&gt;&lt;safe/&gt;\" xmlns:a=\"&quot;/&gt;&lt;evil\">&lt;safe/&gt;\
+            <![CDATA[a]]]]><![CDATA[><evil/>]]></root><!-- raw -->"
+        );
+    }
+
+    #[test]
+    fn begin_elem_ns() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_strict(true);
+        xml.begin_elem("root");
+        xml.begin_elem_ns(Some("x"), "a", "urn:x");
+        xml.attr_qname("x", "id", "1").unwrap();
+        xml.begin_elem("b");
+        xml.end_elem();
+        xml.end_elem();
+        xml.begin_elem("c");
+        let err = xml.attr_qname("x", "id", "1").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::UndeclaredPrefix("x".to_string())));
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<root><x:a xmlns:x=\"urn:x\" x:id=\"1\"><x:b></x:b></x:a><c></c></root>"
+        );
+    }
+
+    #[test]
+    fn attr_with() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.attr_with("size", |w| write!(w, "{}x{}", 3, 4));
+        xml.attr_with("json", |w| write!(w, "{{\"a\": {:?}}}", "<b>"));
+        let err = xml.attr_with("x", |_| Err(std::fmt::Error)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<root size=\"3x4\" json=\"{&quot;a&quot;: &quot;&lt;b&gt;&quot;}\" x=\"\"></root>"
+        );
+    }
+
+    #[test]
+    fn peek() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        assert_eq!(xml.peek(), b"<root");
+        xml.text("t");
+        assert_eq!(xml.peek(), b"<root>t");
+        xml.end_elem();
+        assert_eq!(xml.peek(), b"<root>t</root>");
+    }
+
+    #[test]
+    fn namespace_scoping() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_namespace(Some("a"));
+        xml.begin_elem("root");
+        xml.namespace = Some("b");
+        xml.begin_elem("x");
+        xml.namespace = None;
+        xml.elem_text("y", "t");
+        xml.end_elem();
+        // the manual change stays in effect after the end tag
+        xml.empty_elem("z");
+        xml.begin_elem_ns(Some("c"), "s", "urn:c");
+        xml.namespace = Some("d");
+        xml.empty_elem("t");
+        xml.end_elem();
+        // closing the scoped elem restores the namespace from before it
+        xml.empty_elem("u");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<a:root><b:x><y>t</y></b:x><z/><c:s xmlns:c=\"urn:c\"><d:t/></c:s><u/></a:root>"
+        );
+    }
+
+    #[test]
+    fn optional_elems() {
+        let nickname: Option<String> = None;
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("person");
+        xml.elem_text_opt("name", Some("Ann"));
+        xml.elem_text_opt("nickname", nickname);
+        xml.elem_opt("address", true, |xml| xml.elem_text("city", "Rome"));
+        xml.elem_opt("phone", false, |xml| xml.text("unreachable"));
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<person><name>Ann</name><address><city>Rome</city></address></person>"
+        );
+    }
+
+    #[test]
+    fn cdata_from_reader() {
+        let data = "a]]>b]]]>c]>]]";
+        let expected = "<root><![CDATA[a]]]]><![CDATA[>b]]]]]><![CDATA[>c]>]]]]></root>";
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.cdata_from_reader(data.as_bytes()).unwrap();
+        xml.end_elem();
+        assert_eq!(str::from_utf8(&xml.into_inner()).unwrap(), expected);
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.cdata_from_reader(Trickle(data.as_bytes())).unwrap();
+        xml.end_elem();
+        assert_eq!(str::from_utf8(&xml.into_inner()).unwrap(), expected);
+    }
+
+    #[test]
+    fn elem_and_empty_elem() {
+        fn document(empty: fn(&mut XmlWriter<Vec<u8>>, &str) -> super::Result) -> String {
+            let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+            xml.begin_elem("root");
+            xml.attr("a", "1");
+            empty(&mut xml, "br");
+            xml.close();
+            String::from_utf8(xml.into_inner()).unwrap()
+        }
+
+        let expected = "<root a=\"1\">\n  <br/>\n</root>";
+        assert_eq!(document(|xml, name| xml.elem(name)), expected);
+        assert_eq!(document(|xml, name| xml.empty_elem(name)), expected);
+    }
+
+    #[test]
+    fn no_self_close() {
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.set_self_close(false);
+        xml.begin_elem("soap:Envelope");
+        xml.attr("xmlns:soap", "urn:soap");
+        xml.begin_elem("soap:Header");
+        xml.end_elem();
+        xml.begin_elem("soap:Body");
+        xml.begin_elem("m:Get");
+        xml.attr("id", "1");
+        xml.empty_elem("m:Flag");
+        xml.elem_text("m:Name", "x");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<soap:Envelope xmlns:soap=\"urn:soap\">\n  <soap:Header></soap:Header>\n  <soap:Body>\n    \
+            <m:Get id=\"1\">\n      <m:Flag></m:Flag>\n      <m:Name>x</m:Name>\n    </m:Get>\n  </soap:Body>\n</soap:Envelope>"
+        );
+    }
+
+    #[test]
+    fn write_events() {
+        let events = [
+            Event::Start { name: "root", attrs: &[("a", "1")] },
+            Event::Text("t"),
+            Event::Empty { name: "e", attrs: &[] },
+            Event::End,
+        ];
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.write_events(events).unwrap();
+        let err = xml.write_events([Event::Comment("c"), Event::PI { target: "bad target", data: "" }, Event::Text("x")]).unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::InvalidName("bad target".to_string())));
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root a=\"1\">\n  t\n  <e/>\n</root>\n<!-- c -->");
+    }
+
+    #[test]
+    fn child_before_attr() {
+        fn document(mut xml: XmlWriter<Vec<u8>>) -> String {
+            xml.begin_elem("root");
+            xml.comment("c");
+            let err = xml.attr("a", "1").unwrap_err();
+            assert_eq!(XmlError::from_io(&err), Some(&XmlError::AttrOutsideElement));
+            xml.pi("p", "");
+            assert!(xml.attr_esc("a", "1").is_err());
+            xml.close();
+            String::from_utf8(xml.into_inner()).unwrap()
+        }
+
+        assert_eq!(document(XmlWriter::compact_mode(Vec::new())), "<root><!-- c --><?p?></root>");
+        assert_eq!(document(XmlWriter::pretty_mode(Vec::new())), "<root>\n  <!-- c -->\n  <?p?></root>");
+        assert_eq!(
+            document(XmlWriter::very_pretty_mode(Vec::new())),
+            "<root>\n  <!-- c -->\n  <?p?>\n</root>"
+        );
+    }
+
+    #[test]
+    fn single_quotes() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_quote(Quote::Single);
+        xml.set_minimal_escaping(true);
+        xml.dtd("utf-8");
+        xml.begin_elem("root");
+        xml.ns_decl(&vec![(Some("x"), "urn:x")]);
+        xml.attr("a", "1");
+        xml.attr_esc("b", "\"it's\"");
+        xml.attr_qname("x", "c", "'");
+        xml.attr_with("d", |w| w.write_str("'"));
+        xml.elem_from_map("e", [("f", "'")]);
+        xml.text("'\"");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<?xml version='1.0' encoding='utf-8' ?>\n<root xmlns:x='urn:x' a='1' b='\"it&apos;s\"' \
+            x:c='&apos;' d='&apos;'><e f='&apos;'/>'\"</root>"
+        );
+    }
+
+    #[test]
+    fn with_capacity() {
+        let mut xml = XmlWriter::very_pretty_mode_with_capacity(Vec::with_capacity(64), 16);
+        assert!(xml.stack.capacity() >= 16 && xml.ns_stack.capacity() >= 16);
+        xml.begin_elem("root");
+        xml.empty_elem("a");
+        xml.close();
+        assert_eq!(str::from_utf8(&xml.into_inner()).unwrap(), "<root>\n  <a/>\n</root>");
+
+        let xml = XmlWriter::pretty_mode_with_capacity(Vec::new(), 4);
+        assert!(xml.pretty && !xml.very_pretty && xml.stack.capacity() >= 4);
+    }
+
+    #[test]
+    fn html_escaping() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_html_mode(true);
+        xml.begin_elem("p");
+        xml.attr_esc("title", "it's \"<a>\" &\u{A0}é");
+        xml.text("it's \"<a>\" &\u{A0}é");
+        xml.set_quote(Quote::Single);
+        xml.elem_from_map("img", [("alt", "it's \"")]);
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<p title=\"it's &quot;&lt;a>&quot; &amp;&nbsp;é\">it's \"&lt;a&gt;\" &amp;&nbsp;é<img alt='it&#39;s \"'/></p>"
+        );
+    }
+
+    #[test]
+    fn close_to() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        for name in ["a", "b", "a", "c", "d"] {
+            xml.begin_elem(name);
+        }
+        let err = xml.close_to("x", false).unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::NoSuchOpenElement("x".to_string())));
+        xml.close_to("a", false);
+        xml.text("1");
+        xml.close_to("b", true);
+        xml.text("2");
+        xml.close_to("a", false);
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<a><b><a><c><d></d></c>1</a></b>2</a>");
+    }
+
+    #[test]
+    fn attr_separation() {
+        let cell = AttrSet::new().add("b", "2");
+        for canonical in [false, true] {
+            let mut xml = if canonical {
+                XmlWriter::canonical_mode(Vec::new())
+            } else {
+                XmlWriter::compact_mode(Vec::new())
+            };
+            xml.begin_elem_ns(Some("x"), "root", "urn:x");
+            xml.attr("a", "1");
+            xml.ns_decl(&vec![(None, "urn:d"), (Some("y"), "urn:y")]);
+            xml.apply_attrs(&cell);
+            xml.attr_qname("y", "c", "3");
+            xml.attr_with("d", |w| write!(w, "{}", 4));
+            xml.attr_esc("e", "5");
+            xml.elem_from_map("leaf", [("f", "6"), ("g", "7")]);
+            xml.close();
+
+            let actual = xml.into_inner();
+            let expected = if canonical {
+                "<x:root xmlns=\"urn:d\" xmlns:x=\"urn:x\" xmlns:y=\"urn:y\" a=\"1\" b=\"2\" d=\"4\" e=\"5\" y:c=\"3\">\
+                 <x:leaf f=\"6\" g=\"7\"></x:leaf></x:root>"
+            } else {
+                "<x:root xmlns:x=\"urn:x\" a=\"1\" xmlns=\"urn:d\" xmlns:y=\"urn:y\" b=\"2\" y:c=\"3\" d=\"4\" e=\"5\">\
+                 <x:leaf f=\"6\" g=\"7\"/></x:root>"
+            };
+            assert_eq!(str::from_utf8(&actual).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn embed() {
+        let mut part = XmlWriter::compact_mode(Vec::new());
+        part.begin_elem("item");
+        part.text("a");
+        part.close();
+        let part = part.into_inner();
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.embed("plugin", &part);
+        xml.embed("empty", b"");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root><plugin><item>a</item></plugin><empty></empty></root>");
+
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.embed("plugin", b"<item>\n  <value>a</value>\n</item>\n");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<root>\n  <plugin>\n    <item>\n      <value>a</value>\n    </item>\n  </plugin>\n</root>"
+        );
+
+        let mut xml = XmlWriter::pretty_mode(Vec::new());
+        let err = xml.embed("plugin", b"\xff").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(xml.into_inner().is_empty());
+    }
+
+    #[test]
+    fn comments_count_as_children() {
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.begin_elem("x");
+        xml.comment("c");
+        xml.end_elem();
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root>\n  <x>\n    <!-- c -->\n  </x>\n</root>");
+
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.set_comments_count_as_children(false);
+        xml.begin_elem("root");
+        xml.begin_elem("x");
+        xml.comment("c");
+        xml.comment("d");
+        xml.end_elem();
+        xml.begin_elem("y");
+        xml.comment("e");
+        xml.empty_elem("z");
+        xml.end_elem();
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<root>\n  <x/>\n  <!-- c -->\n  <!-- d -->\n  <y>\n    <!-- e -->\n    <z/>\n  </y>\n</root>"
+        );
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_comments_count_as_children(false);
+        xml.begin_elem("x");
+        xml.comment("c");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<x><!-- c --></x>");
+    }
+
+    #[test]
+    fn consecutive_text() {
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.begin_elem("a");
+        xml.text("one ");
+        xml.text("two");
+        xml.end_elem();
+        xml.begin_elem("b");
+        xml.text("x");
+        xml.end_elem();
+        xml.text("y");
+        xml.entity("amp");
+        xml.text("z");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<root>\n  <a>\n    one two\n  </a>\n  <b>\n    x\n  </b>\n  y&amp;z\n</root>"
+        );
+    }
+
+    #[test]
+    fn namespace_scope() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        assert_eq!(xml.namespace_scope(), &[]);
+        assert_eq!(xml.current_namespace(), None);
+        xml.begin_elem("root");
+        xml.begin_elem_ns(Some("a"), "x", "urn:a");
+        xml.begin_elem("y");
+        assert_eq!(xml.namespace_scope(), &[None, Some("a"), Some("a")]);
+        assert_eq!(xml.current_namespace(), Some("a"));
+        xml.end_elem();
+        xml.end_elem();
+        assert_eq!(xml.namespace_scope(), &[None]);
+        assert_eq!(xml.current_namespace(), None);
+        xml.close();
+        assert_eq!(xml.namespace_scope(), &[]);
+    }
+
+    #[test]
+    fn datetime() {
+        struct Utc(u32);
+        impl std::fmt::Display for Utc {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "2024-01-0{}T12:00:00Z", self.0)
+            }
+        }
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("event");
+        xml.attr_datetime("at", &Utc(1));
+        xml.text_datetime(&Utc(2));
+        xml.text_datetime(&"<now>");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<event at=\"2024-01-01T12:00:00Z\">2024-01-02T12:00:00Z&lt;now&gt;</event>"
+        );
+    }
+
+    #[test]
+    fn text_list() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.wrapped_text_list("tags", "tag", ["a", "<b>"]);
+        xml.wrapped_text_list("none", "tag", Vec::<String>::new());
+        xml.text_list("id", vec![String::from("1")]);
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<root><tags><tag>a</tag><tag>&lt;b&gt;</tag></tags><none></none><id>1</id></root>"
+        );
+    }
+
+    #[test]
+    fn trailing_newline() {
+        let mut xml = XmlWriter::pretty_mode(Vec::new());
+        xml.set_trailing_newline(true);
+        xml.begin_elem("root");
+        xml.elem_text("a", "1");
+        xml.close();
+        xml.close();
+        xml.finish();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root>\n  <a>1</a></root>\n");
+
+        // nodes after the root keep the line break at the end
+        let modes = [
+            (XmlWriter::very_pretty_mode(Vec::new()), "<a/>\n<!-- after -->\n<?pi?>\n"),
+            (XmlWriter::compact_mode(Vec::new()), "<a/>\n<!-- after --><?pi?>\n"),
+        ];
+        for (mut xml, expected) in modes {
+            xml.set_trailing_newline(true);
+            xml.empty_elem("a");
+            xml.close();
+            xml.comment("after");
+            xml.pi("pi", "");
+            xml.close();
+
+            let actual = xml.into_inner();
+            assert_eq!(str::from_utf8(&actual).unwrap(), expected);
+        }
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_trailing_newline(true);
+        xml.set_empty_root(Some("empty"));
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<empty/>\n");
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_trailing_newline(true);
+        xml.close();
+        assert!(xml.into_inner().is_empty());
+    }
+
+    #[test]
+    fn partial_flush() {
+        // the flush is attempted only at node boundaries
+        let mut xml = XmlWriter::compact_mode(Unflushable);
+        xml.begin_elem("root");
+        xml.flush_element();
+        xml.write("<raw a=\"&amp");
+        assert!(xml.partial_flush().is_ok());
+        xml.write(";\"");
+        assert!(xml.partial_flush().is_ok());
+        xml.write("/>");
+        assert!(xml.partial_flush().is_err());
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.attr("a", "1");
+        xml.partial_flush();
+        assert_eq!(xml.peek(), b"<root a=\"1\">");
+
+        // raw output is finished by the markup of the writer itself
+        let mut xml = XmlWriter::compact_mode(Unflushable);
+        xml.begin_elem("a");
+        xml.flush_element();
+        xml.write("<b");
+        assert!(xml.partial_flush().is_ok());
+        xml.end_elem();
+        assert!(xml.partial_flush().is_err());
+
+        let mut xml = XmlWriter::compact_mode(Unflushable);
+        xml.begin_elem("a");
+        xml.flush_element();
+        xml.write("x ; y & z");
+        assert!(xml.partial_flush().is_err());
+        xml.write("&am");
+        assert!(xml.partial_flush().is_ok());
+        xml.text("p; b");
+        assert!(xml.partial_flush().is_err());
+    }
+
+    #[test]
+    fn qualified_elem_names() {
+        for namespace in [None, Some("x")] {
+            let mut xml = XmlWriter::compact_mode(Vec::new());
+            xml.namespace = namespace;
+            xml.begin_elem("soap:Envelope");
+            xml.begin_elem("Body");
+            xml.elem_text("soap:Fault", "f");
+            xml.empty_elem("soap:Header");
+            xml.close();
+
+            let actual = xml.into_inner();
+            let expected = match namespace {
+                None => "<soap:Envelope><Body><soap:Fault>f</soap:Fault><soap:Header/></Body></soap:Envelope>",
+                Some(_) => "<soap:Envelope><x:Body><soap:Fault>f</soap:Fault><soap:Header/></x:Body></soap:Envelope>",
+            };
+            assert_eq!(str::from_utf8(&actual).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn char_filter() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_char_filter(Box::new(|c| c.is_ascii_lowercase() || c == ' ' || c == 'é'));
+        xml.begin_elem("root");
+        xml.attr_esc("a", "Aé\"");
+        xml.text("ab Z€ <é>");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<root a=\"&#x41;é&quot;\">ab &#x5A;&#x20AC; &lt;é&gt;</root>"
+        );
+    }
+
+    #[test]
+    fn attrs() {
+        let sorted = BTreeMap::from([("b", "2"), ("a", "<1>")]);
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.attrs(&sorted);
+        xml.attrs(vec![(String::from("z"), String::from("3")), (String::from("y"), String::from("4"))]);
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root a=\"&lt;1&gt;\" b=\"2\" z=\"3\" y=\"4\"></root>");
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        let err = xml.attrs([("a", "1")]).unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::AttrOutsideElement));
+    }
+
+    #[test]
+    fn begin_qname() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.namespace = Some("x");
+        xml.begin_qname(Some("soap"), "Envelope");
+        xml.ns_decl(&vec![(Some("soap"), "urn:soap")]);
+        assert_eq!(xml.current_namespace(), Some("soap"));
+        xml.begin_qname(None, "Body");
+        xml.begin_elem("item");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<soap:Envelope xmlns:soap=\"urn:soap\"><Body><x:item></x:item></Body></soap:Envelope>"
+        );
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        let err = xml.begin_qname(Some("a b"), "c").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::InvalidName("a b".to_string())));
+        let err = xml.begin_qname(Some("a"), "b c").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::InvalidName("b c".to_string())));
+        let err = xml.begin_qname(Some("a"), "b:c").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::InvalidName("b:c".to_string())));
+        let err = xml.begin_qname(None, "b:c").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::InvalidName("b:c".to_string())));
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "");
+    }
+
+    #[test]
+    fn preserve_mixed_content() {
+        let write = |xml: &mut XmlWriter<Vec<u8>>| {
+            xml.begin_elem("doc");
+            xml.begin_elem("p");
+            xml.text("some ");
+            xml.begin_elem("b");
+            xml.begin_elem("i");
+            xml.text("bold");
+            xml.end_elem();
+            xml.end_elem();
+            xml.empty_elem("br");
+            xml.cdata("x");
+            xml.text(" tail");
+            xml.end_elem();
+            xml.begin_elem("list");
+            xml.text("  ");
+            xml.elem_text("item", "1");
+            xml.end_elem();
+            xml.close();
+        };
+
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.set_preserve_mixed_content(true);
+        write(&mut xml);
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<doc>\n  <p>some <b><i>bold</i></b><br/><![CDATA[x]]> tail</p>\n  <list>\n      \n    <item>1</item>\n  </list>\n</doc>"
+        );
+
+        let mut xml = XmlWriter::pretty_mode(Vec::new());
+        xml.set_preserve_mixed_content(true);
+        write(&mut xml);
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<doc>\n  <p>some <b><i>bold</i></b><br/><![CDATA[x]]> tail</p>\n  <list>  \n    <item>1</item></list></doc>"
+        );
+    }
 
     #[test]
-    fn compact() {
-        let nsmap = vec![
-            (None, "http://localhost/"),
-            (Some("st"), "http://127.0.0.1/"),
-        ];
+    fn script_style() {
         let mut xml = XmlWriter::compact_mode(Vec::new());
-        xml.begin_elem("OTDS");
-        xml.ns_decl(&nsmap);
-        xml.comment("nice to see you");
-        xml.namespace = Some("st");
-        xml.empty_elem("success");
-        xml.begin_elem("node");
-        xml.attr_esc("name", "\"123\"");
-        xml.attr("id", "abc");
-        xml.attr("'unescaped'", "\"123\""); // this WILL generate invalid xml
-        xml.text("'text'");
+        xml.begin_elem("head");
+        xml.script("if (a < b && c[d[0]]>1) {}");
+        xml.style("p > a { color: red }");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<head><script>//<![CDATA[\nif (a < b && c[d[0]]]]><![CDATA[>1) {}\n//]]></script>\
+             <style>/*<![CDATA[*/\np > a { color: red }\n/*]]>*/</style></head>"
+        );
+    }
+
+    #[test]
+    fn begin_compact() {
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.elem_text("title", "t");
+        xml.begin_compact("data", |xml| {
+            xml.begin_elem("row");
+            xml.elem_text("v", "1");
+            xml.empty_elem("e");
+            xml.end_elem()
+        });
+        xml.begin_elem("after");
+        xml.empty_elem("e");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<root>\n  <title>t</title>\n  <data><row><v>1</v><e/></row></data>\n  <after>\n    <e/>\n  </after>\n</root>"
+        );
+    }
+
+    #[test]
+    fn attr_flags() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("a");
+        xml.attr_flags("rel", &[("nofollow", true), ("external", false), ("noopener", true)]);
+        xml.attr_flags("class", &[("active", false)]);
+        xml.attr_flags("data-x", &[("a&b", true)]);
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<a rel=\"nofollow noopener\" data-x=\"a&amp;b\"></a>");
+    }
+
+    #[test]
+    fn attr_escaping_contract() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.attr_raw("raw", "a &amp; b");
+        xml.attr_pre_escaped("pre", "a &amp; b");
+        xml.attr_esc("esc", "a &amp; b <c>");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<root raw=\"a &amp; b\" pre=\"a &amp; b\" esc=\"a &amp;amp; b &lt;c&gt;\"></root>"
+        );
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        let err = xml.attr_raw("raw", "v").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::AttrOutsideElement));
+        xml.set_safe_mode(true);
+        xml.begin_elem("root");
+        let err = xml.attr_raw("raw", "a &amp; b").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::RawWriteForbidden));
+        let err = xml.attr_pre_escaped("pre", "a &amp; b").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::RawWriteForbidden));
+    }
+
+    #[test]
+    fn observer() {
+        let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        let log = events.clone();
+        xml.set_observer(Box::new(move |event| log.lock().unwrap().push(format!("{:?}", event))));
+        xml.begin_elem("root");
+        xml.comment("c");
+        xml.elem_text("a", "1");
+        xml.empty_elem("b");
+        xml.cdata("x");
+        xml.pi("pi", "");
+        xml.text("t");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root><!-- c --><a>1</a><b/><![CDATA[x]]><?pi?>t</root>");
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "Open(\"root\")", "Comment", "Open(\"a\")", "Text", "Close(\"a\")", "Empty(\"b\")",
+                "CData", "Pi(\"pi\")", "Text", "Close(\"root\")",
+            ]
+        );
+    }
+
+    #[test]
+    fn namespace_context() {
+        let mut ctx = NamespaceContext::new();
+        ctx.declare(None, "urn:html");
+        ctx.declare(Some("x"), "urn:x");
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem_ctx(&ctx, "urn:html", "html");
+        xml.attr_ctx(&ctx, "urn:x", "id", "1");
+        ctx.push_scope();
+        ctx.declare(Some("x"), "urn:other");
+        ctx.declare(Some("y"), "urn:x");
+        xml.begin_elem_ctx(&ctx, "urn:x", "a");
+        xml.begin_elem_ctx(&ctx, "urn:other", "b");
+        let err = xml.attr_ctx(&ctx, "urn:html", "c", "2").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::UnboundNamespace("urn:html".to_string())));
         xml.end_elem();
-        xml.namespace = None;
-        xml.begin_elem("stuff");
-        xml.cdata("blablab");
-        // xml.end_elem();
-        // xml.end_elem();
+        xml.end_elem();
+        ctx.pop_scope();
+        assert_eq!(ctx.prefix("urn:x"), Some(Some("x")));
+        assert_eq!(ctx.uri(Some("y")), None);
+        // the outermost scope is kept
+        ctx.pop_scope();
+        assert_eq!(ctx.uri(Some("x")), Some("urn:x"));
+        xml.begin_elem_ctx(&ctx, "urn:x", "d");
         xml.close();
-        xml.flush();
 
         let actual = xml.into_inner();
-        println!("{}", str::from_utf8(&actual).unwrap());
-        assert_eq!(str::from_utf8(&actual).unwrap(), "<OTDS xmlns=\"http://localhost/\" xmlns:st=\"http://127.0.0.1/\"><!-- nice to see you --><st:success/><st:node name=\"&quot;123&quot;\" id=\"abc\" \'unescaped\'=\"\"123\"\">&apos;text&apos;</st:node><stuff><![CDATA[blablab]]></stuff></OTDS>");
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<html xmlns=\"urn:html\" xmlns:x=\"urn:x\" x:id=\"1\"><y:a xmlns:y=\"urn:x\">\
+             <x:b xmlns:x=\"urn:other\"></x:b></y:a><x:d></x:d></html>"
+        );
     }
 
     #[test]
-    fn pretty() {
-        let nsmap = vec![
-            (None, "http://localhost/"),
-            (Some("st"), "http://127.0.0.1/"),
-        ];
-        let mut xml = XmlWriter::pretty_mode(Vec::new());
-        xml.begin_elem("OTDS");
-        xml.ns_decl(&nsmap);
-        xml.comment("nice to see you");
-        xml.namespace = Some("st");
-        xml.empty_elem("success");
-        xml.begin_elem("node");
-        xml.attr_esc("name", "\"123\"");
-        xml.attr("id", "abc");
-        xml.attr("'unescaped'", "\"123\""); // this WILL generate invalid xml
-        xml.text("'text'");
+    fn text_substitutions() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_text_substitutions(&[('©', "&#169;"), ('™', "&#8482;"), ('<', "LT")]);
+        xml.begin_elem("root");
+        xml.attr_esc("a", "©");
+        xml.text("Acme™ © 2024 <x>");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root a=\"©\">Acme&#8482; &#169; 2024 &lt;x&gt;</root>");
+    }
+
+    #[test]
+    fn namespaced_self_close() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.namespace = Some("ns");
+        xml.begin_elem("root");
+        xml.empty_elem("name");
+        xml.set_self_close(false);
+        xml.empty_elem("open");
+        xml.namespace = None;
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<ns:root><ns:name/><ns:open></ns:open></ns:root>");
+
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.namespace = Some("ns");
+        xml.begin_elem("root");
+        xml.begin_elem("implicit");
+        xml.namespace = Some("other");
         xml.end_elem();
+        xml.set_explicit_close_root(true);
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<ns:root>\n  <ns:implicit/>\n</ns:root>");
+
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.namespace = Some("ns");
+        xml.set_explicit_close_root(true);
+        xml.begin_elem("root");
         xml.namespace = None;
-        xml.begin_elem("stuff");
-        xml.cdata("blablab");
-        // xml.end_elem();
-        // xml.end_elem();
         xml.close();
-        xml.flush();
 
         let actual = xml.into_inner();
-        println!("{}", str::from_utf8(&actual).unwrap());
-        assert_eq!(str::from_utf8(&actual).unwrap(), "<OTDS xmlns=\"http://localhost/\" xmlns:st=\"http://127.0.0.1/\">\n  <!-- nice to see you -->\n  <st:success/>\n  <st:node name=\"&quot;123&quot;\" id=\"abc\" \'unescaped\'=\"\"123\"\">&apos;text&apos;</st:node>\n  <stuff><![CDATA[blablab]]></stuff></OTDS>");
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<ns:root></ns:root>");
     }
 
     #[test]
-    fn very_pretty() {
-        let nsmap = vec![
-            (None, "http://localhost/"),
-            (Some("st"), "http://127.0.0.1/"),
+    fn inline_text_leaves() {
+        let write = |xml: &mut XmlWriter<Vec<u8>>| {
+            xml.begin_elem("root");
+            xml.begin_elem("a");
+            xml.text("value");
+            xml.end_elem();
+            xml.begin_elem("b");
+            xml.empty_elem("c");
+            xml.text("tail");
+            xml.close();
+        };
+
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        write(&mut xml);
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<root>\n  <a>\n    value\n  </a>\n  <b>\n    <c/>\n    tail\n  </b>\n</root>"
+        );
+
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.set_inline_text_leaves(true);
+        write(&mut xml);
+        let actual = xml.into_inner();
+        let mut readable = XmlWriter::readable_mode(Vec::new());
+        write(&mut readable);
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<root>\n  <a>value</a>\n  <b>\n    <c/>\n    tail\n  </b>\n</root>"
+        );
+        assert_eq!(actual, readable.into_inner());
+    }
+
+    #[test]
+    fn decl_auto() {
+        struct Latin1(Vec<u8>);
+        impl Write for Latin1 {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        impl EncodingAware for Latin1 {
+            fn encoding(&self) -> &str {
+                "ISO-8859-1"
+            }
+        }
+        struct Long(Vec<u8>);
+        impl Write for Long {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        impl EncodingAware for Long {
+            fn encoding(&self) -> &str {
+                "x-a-rather-long-encoding-name-exceeding-the-size-of-one-chunk"
+            }
+        }
+
+        let mut xml = XmlWriter::compact_mode(Long(Vec::new()));
+        xml.decl_auto().unwrap();
+        let actual = xml.into_inner().0;
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<?xml version=\"1.0\" encoding=\"x-a-rather-long-encoding-name-exceeding-the-size-of-one-chunk\" ?>\n"
+        );
+
+        let mut xml = XmlWriter::compact_mode(Latin1(Vec::new()));
+        xml.decl_auto();
+        xml.empty_elem("root");
+
+        let actual = xml.into_inner().0;
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<?xml version=\"1.0\" encoding=\"ISO-8859-1\" ?>\n<root/>");
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.decl_auto();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<?xml version=\"1.0\" encoding=\"utf-8\" ?>\n");
+
+        // wrappers report the encoding of the inner sink
+        let mut inner = Latin1(Vec::new());
+        let mut xml = XmlWriter::compact_mode(io::BufWriter::new(&mut inner));
+        xml.decl_auto();
+        drop(xml.into_inner());
+        assert_eq!(str::from_utf8(&inner.0).unwrap(), "<?xml version=\"1.0\" encoding=\"ISO-8859-1\" ?>\n");
+    }
+
+    #[test]
+    fn elem_text_policy() {
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.elem_text_policy("name", "a < b", FieldPolicy::Escape);
+        xml.elem_text_policy("html", "<p>x]]>y</p>", FieldPolicy::CData);
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<root>\n  <name>a &lt; b</name>\n  <html><![CDATA[<p>x]]]]><![CDATA[>y</p>]]></html>\n</root>"
+        );
+    }
+
+    #[test]
+    fn escape_astral_chars() {
+        let text = "😀&𠀀<é>😀\"'x🎉";
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.attr_esc("a", text);
+        xml.text(text);
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<root a=\"😀&amp;𠀀&lt;é&gt;😀&quot;&apos;x🎉\">😀&amp;𠀀&lt;é&gt;😀&quot;&apos;x🎉</root>"
+        );
+
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_ascii_only(true);
+        xml.text(text);
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "&#x1F600;&amp;&#x20000;&lt;&#xE9;&gt;&#x1F600;&quot;&apos;x&#x1F389;"
+        );
+    }
+
+    #[test]
+    fn element_or() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        for cond in [true, false] {
+            xml.element_or(cond, "yes", |xml| xml.text("1"), |xml| xml.elem_text("no", "0"));
+            xml.when(cond, |xml| xml.empty_elem("when"));
+        }
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root><yes>1</yes><when/><no>0</no></root>");
+    }
+
+    #[test]
+    fn attr_names() {
+        let owned = String::from("b");
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.attr("a", "1");
+        xml.attr(&owned, "2");
+        xml.attr_esc(String::from("c"), "3");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root a=\"1\" b=\"2\" c=\"3\"></root>");
+    }
+
+    #[test]
+    fn metadata_comment() {
+        let mut xml = XmlWriter::pretty_mode(Vec::new());
+        xml.metadata_comment(&[("generated", "2024-01-01T12:00:00Z"), ("tool", "gen --fast <v1>"), ("x", "a---b")]);
+        xml.empty_elem("root");
+
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<!-- generated: 2024-01-01T12:00:00Z, tool: gen - -fast &lt;v1&gt;, x: a- - -b -->\n<root/>"
+        );
+    }
+
+    #[test]
+    fn strict_text_at_root() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.set_strict(true);
+        xml.text("\n  ").unwrap();
+        let err = xml.text("stray").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::TextAtRoot));
+        let err = xml.cdata("stray").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::TextAtRoot));
+        let err = xml.entity("amp").unwrap_err();
+        assert_eq!(XmlError::from_io(&err), Some(&XmlError::TextAtRoot));
+        xml.begin_elem("root").unwrap();
+        xml.text("inside").unwrap();
+        xml.cdata("inside").unwrap();
+        xml.end_elem().unwrap();
+        xml.close().unwrap();
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "\n  <root>inside<![CDATA[inside]]></root>");
+    }
+
+    #[test]
+    fn attr_wrap() {
+        let styles = [
+            (AttrWrapStyle::AlignUnderName, "<root>\n  <link href=\"a\"\n   rel=\"b\"\n   type=\"c\"/>\n</root>"),
+            (AttrWrapStyle::AlignUnderFirstAttr, "<root>\n  <link href=\"a\"\n        rel=\"b\"\n        type=\"c\"/>\n</root>"),
+            (AttrWrapStyle::FixedIndent(4), "<root>\n  <link href=\"a\"\n      rel=\"b\"\n      type=\"c\"/>\n</root>"),
         ];
+        for (style, expected) in styles {
+            let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+            xml.set_attr_wrap(Some(style));
+            xml.begin_elem("root");
+            xml.begin_elem("link");
+            xml.attr("href", "a");
+            xml.attr("rel", "b");
+            xml.attr("type", "c");
+            xml.end_elem();
+            xml.close();
+            let actual = xml.into_inner();
+            assert_eq!(str::from_utf8(&actual).unwrap(), expected);
+        }
+
         let mut xml = XmlWriter::very_pretty_mode(Vec::new());
-        xml.begin_elem("OTDS");
-        xml.ns_decl(&nsmap);
-        xml.comment("nice to see you");
-        xml.namespace = Some("st");
-        xml.empty_elem("success");
-        xml.begin_elem("node");
-        xml.attr_esc("name", "\"123\"");
-        xml.attr("id", "abc");
-        xml.attr("'unescaped'", "\"123\""); // this WILL generate invalid xml
-        xml.text("'text'");
+        xml.set_attr_wrap(Some(AttrWrapStyle::AlignUnderFirstAttr));
+        xml.set_attr_order(&["b", "a"]);
+        xml.begin_elem("x");
+        xml.attr("a", "1");
+        xml.attr("b", "2");
         xml.end_elem();
-        xml.namespace = None;
-        xml.begin_elem("stuff");
-        xml.cdata("blablab");
-        // xml.end_elem();
-        // xml.end_elem();
         xml.close();
-        xml.flush();
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<x b=\"2\"\n   a=\"1\"/>");
+    }
 
+    #[test]
+    fn explicit_close_ns() {
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.set_explicit_close_ns(true);
+        xml.begin_elem("root");
+        xml.begin_elem("a");
+        xml.ns_decl(&vec![(Some("x"), "urn:x")]);
+        xml.end_elem();
+        xml.begin_elem("b");
+        xml.attr("id", "1");
+        xml.end_elem();
+        xml.close();
         let actual = xml.into_inner();
-        println!("{}", str::from_utf8(&actual).unwrap());
-        assert_eq!(str::from_utf8(&actual).unwrap(), "<OTDS xmlns=\"http://localhost/\" xmlns:st=\"http://127.0.0.1/\">\n  <!-- nice to see you -->\n  <st:success/>\n  <st:node name=\"&quot;123&quot;\" id=\"abc\" \'unescaped\'=\"\"123\"\">\n    &apos;text&apos;\n  </st:node>\n  <stuff>\n    <![CDATA[blablab]]>\n  </stuff>\n</OTDS>");
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<root>\n  <a xmlns:x=\"urn:x\"></a>\n  <b id=\"1\"/>\n</root>"
+        );
     }
 
     #[test]
-    fn comment() {
-        let mut xml = XmlWriter::pretty_mode(Vec::new());
-        xml.comment("comment");
+    fn write_indent() {
+        let mut xml = XmlWriter::very_pretty_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.begin_elem("list");
+        xml.write_indent();
+        xml.write("<raw/>");
+        xml.elem_text("item", "a");
+        xml.close();
+        let actual = xml.into_inner();
+        assert_eq!(
+            str::from_utf8(&actual).unwrap(),
+            "<root>\n  <list>\n    <raw/>\n    <item>a</item>\n  </list>\n</root>"
+        );
 
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        xml.begin_elem("root");
+        xml.write_indent();
+        xml.write("<raw/>");
+        xml.close();
         let actual = xml.into_inner();
-        assert_eq!(str::from_utf8(&actual).unwrap(), "<!-- comment -->");
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root><raw/></root>");
+    }
+
+    #[test]
+    fn mode() {
+        let mut xml = XmlWriter::compact_mode(Vec::new());
+        assert_eq!(xml.mode(), OutputMode::Compact);
+        xml.set_pretty_mode();
+        assert_eq!(xml.mode(), OutputMode::Pretty);
+        xml.set_very_pretty_mode();
+        assert_eq!(xml.mode(), OutputMode::VeryPretty);
+        xml.set_readable_mode();
+        assert_eq!(xml.mode(), OutputMode::VeryPretty);
+        xml.set_canonical_mode();
+        assert_eq!(xml.mode(), OutputMode::Compact);
+    }
+
+    #[test]
+    fn canonical_cdata() {
+        let mut xml = XmlWriter::canonical_mode(Vec::new());
+        xml.begin_elem("doc");
+        xml.cdata("<x>");
+        xml.cdata_from_reader("a & b".as_bytes());
+        xml.char_data("]]>", true);
+        xml.elem_text_policy("f", "<y>", FieldPolicy::CData);
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<doc>&lt;x&gt;a &amp; b]]&gt;<f>&lt;y&gt;</f></doc>");
+    }
+
+    #[test]
+    fn send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<XmlWriter<Vec<u8>>>();
+        assert_send_sync::<XmlWriter<std::fs::File>>();
     }
 }